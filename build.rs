@@ -1,71 +1,68 @@
 extern crate phf_codegen;
 
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
+/// Embeds the IEEE MA-L registry as a map from OUI to vendor. Point `OUI_DB`
+/// at a full `oui.csv` or `oui.txt` from standards-oui.ieee.org; without it
+/// the sample in `data/oui.csv` is used. See the README.
 fn main() {
-    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("friendly_names.rs");
+    println!("cargo:rerun-if-env-changed=OUI_DB");
+    let db = env::var_os("OUI_DB").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("data/oui.csv"));
+    println!("cargo:rerun-if-changed={}", db.display());
+    let file = File::open(&db).unwrap_or_else(|e| panic!("failed to open OUI database {}: {}", db.display(), e));
+    let mut vendors = BTreeMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.unwrap_or_else(|e| panic!("failed to read OUI database {}: {}", db.display(), e));
+        if let Some((oui, vendor)) = parse_line(&line) {
+            vendors.entry(oui).or_insert(vendor);
+        }
+    }
+
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("oui.rs");
     let mut file = BufWriter::new(File::create(&path).unwrap());
+    write!(&mut file, "static OUI_VENDORS: phf::Map<u32, &'static str> = ").unwrap();
+    let mut map = phf_codegen::Map::new();
+    for (oui, vendor) in &vendors {
+        map.entry(*oui, &format!("{:?}", vendor));
+    }
+    map.build(&mut file).unwrap();
+    writeln!(&mut file, ";").unwrap();
+}
 
-    write!(&mut file, "static FRIENDLY_NAMES: phf::Set<&'static str> = ").unwrap();
-    phf_codegen::Set::new()
-        .entry("adriennes-mbp")
-        .entry("adriennsmacbook")
-        .entry("adriennssiphone")
-        .entry("amaras-ipad")
-        .entry("ashleys-ipad")
-        .entry("ashleys-iphone")
-        .entry("ashleysplewatch")
-        .entry("benjaminsiphone")
-        .entry("bethany-i5")
-        .entry("bethanys-air")
-        .entry("bobbybonsiphone")
-        .entry("bobbysipadmini")
-        .entry("briannas-iphone")
-        .entry("brittanys-ipad")
-        .entry("brittanysiphone")
-        .entry("courtneysiphone")
-        .entry("crystalesiphone")
-        .entry("crystals-ipad")
-        .entry("dianas-ipad")
-        .entry("elainas-iphone")
-        .entry("ellies-iphone")
-        .entry("ericas-ipad")
-        .entry("hanks-ipad")
-        .entry("joe")
-        .entry("joshuas-ipad")
-        .entry("joshuas-iphone")
-        .entry("katharines-ipad")
-        .entry("kristens-ipad")
-        .entry("kristens-iphone")
-        .entry("kristi-anderson")
-        .entry("kristismithipad")
-        .entry("kristyns-iphone")
-        .entry("krystals-ipad")
-        .entry("lorrie")
-        .entry("lucindas-ipad")
-        .entry("mareans-ipad")
-        .entry("mareans-iphone")
-        .entry("meaganbtsiphone")
-        .entry("meagans-ipad")
-        .entry("megans-iphone")
-        .entry("missythang")
-        .entry("monicas-iphone")
-        .entry("monicas-mbp")
-        .entry("olivias-iphone")
-        .entry("olivias-phone")
-        .entry("pauls-ipad")
-        .entry("remastrssiphone")
-        .entry("robinhansiphone")
-        .entry("robins-ipad")
-        .entry("robins-iphone")
-        .entry("roslyns-iphone")
-        .entry("tolson")
-        .entry("wendys-ipad")
-        .build(&mut file)
-        .unwrap();
-    write!(&mut file, ";\n").unwrap();
+/// Parses an assignment from either `MA-L,B827EB,Raspberry Pi Foundation,...`
+/// in oui.csv or `B8-27-EB   (hex)  Raspberry Pi Foundation` in oui.txt.
+fn parse_line(line: &str) -> Option<(u32, String)> {
+    let (oui, vendor) = if line.starts_with("MA-L,") {
+        let mut fields = csv_fields(line).into_iter().skip(1);
+        (fields.next()?, fields.next()?)
+    } else {
+        let hex = line.find("(hex)")?;
+        (line[..hex].trim().replace('-', ""), line[hex + "(hex)".len()..].to_string())
+    };
+    if oui.len() != 6 {
+        return None;
+    }
+    Some((u32::from_str_radix(&oui, 16).ok()?, vendor.trim().to_string()))
 }
 
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
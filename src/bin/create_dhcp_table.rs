@@ -1,75 +1,365 @@
 extern crate parse_logs;
-#[macro_use]
 extern crate structopt;
 extern crate rusqlite;
+extern crate chrono;
+extern crate chrono_tz;
 
 use structopt::StructOpt;
 use std::error::Error;
 use std::path::PathBuf;
-use std::io::BufReader;
-use std::fs::File;
-use std::io::BufRead;
 use parse_logs::dhcp::{LogEntry, DhcpMsg};
+use parse_logs::dhcp6;
+use parse_logs::{MacAddr, ParseStage, TimestampOptions};
+use parse_logs::inventory::{Device, Inventory, Seen};
+use parse_logs::ledger::{Ledger, LogFile};
+use parse_logs::output::{self, Config, Mode};
+use parse_logs::walk::{FileFilter, SortBy};
+use std::collections::BTreeMap;
+use chrono_tz::Tz;
+use std::net::{IpAddr, Ipv4Addr};
+use chrono::{DateTime, Utc};
+use std::fs::File;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
 
 #[derive(StructOpt, Debug)]
 struct Opt {
+    /// Timezone of log timestamps that carry no UTC offset.
+    #[structopt(long = "timezone", default_value = "UTC")]
+    timezone: Tz,
+
+    /// Keep running and insert lines as they are appended to the files,
+    /// following them across rotation like `tail -F`. Directories are
+    /// walked again on every check, so logs created in them are read too.
+    #[structopt(long = "follow")]
+    follow: bool,
+
+    /// Seconds to wait between checks for new lines when following.
+    #[structopt(long = "poll_interval", default_value = "1")]
+    poll_interval: u64,
+
+    /// Glob patterns, relative to each directory given, of the files to
+    /// read from it. All files are read when there are none.
+    #[structopt(long = "include")]
+    include: Vec<String>,
+
+    /// Glob patterns of files to leave out when reading directories.
+    #[structopt(long = "exclude")]
+    exclude: Vec<String>,
+
+    /// The order to read the files in a directory in: name or mtime.
+    #[structopt(long = "sort", default_value = "name")]
+    sort: SortBy,
+
+    /// JSON file of output settings, with any of the keys database,
+    /// table_prefix, mode and failures_log. Flags override it.
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// SQLite database to write to. Defaults to output.db.
+    #[structopt(long = "output", parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Prefix for the names of the tables written, so that several sites
+    /// can share a database.
+    #[structopt(long = "table_prefix")]
+    table_prefix: Option<String>,
+
+    /// What to do with tables that already exist: create, append or
+    /// replace. Defaults to append.
+    #[structopt(long = "mode")]
+    mode: Option<Mode>,
+
+    /// File to append the lines that fail to parse to, which replace
+    /// mode empties first. Defaults to failures.log.
+    #[structopt(long = "failures_log", parse(from_os_str))]
+    failures_log: Option<PathBuf>,
+
+    /// Log files, or directories to read the logs under.
     #[structopt(name = "FILE", parse(from_os_str))]
     files: Vec<PathBuf>,
 }
 
+/// The names of the tables written, after prefixing.
+struct Tables {
+    dhcp_logs: String,
+    devices: String,
+    device_hostnames: String,
+    device_ips: String,
+}
+
+impl Tables {
+    fn new(config: &Config) -> Result<Self, Box<dyn Error>> {
+        Ok(Tables {
+            dhcp_logs: config.table_name("dhcp_logs")?,
+            devices: config.table_name("devices")?,
+            device_hostnames: config.table_name("device_hostnames")?,
+            device_ips: config.table_name("device_ips")?,
+        })
+    }
+
+    fn all(&self) -> [&str; 4] {
+        [&self.dhcp_logs, &self.devices, &self.device_hostnames, &self.device_ips]
+    }
+}
+
 struct Tx<'a>{
     tx: rusqlite::Transaction<'a>,
+    tables: &'a Tables,
 }
 
 impl<'a> Tx<'a> {
-    fn new(db: &mut rusqlite::Connection) -> Result<Tx, Box<Error>> {
-        let mut tx = Tx{tx: db.transaction()?};
-        tx.create_table()?;
-        Ok(tx)
+    fn new(db: &'a mut rusqlite::Connection, tables: &'a Tables) -> Result<Tx<'a>, Box<dyn Error>> {
+        Ok(Tx{tx: db.transaction()?, tables})
     }
 
-    fn create_table(&mut self) -> Result<(), Box<Error>> {
-        self.tx.execute("CREATE TABLE dhcp_logs (datetime TEXT, ip_addr TEXT, mac_addr TEXT);", &[])?;
+    /// Creates or updates the tables, once before the first insert.
+    fn create_tables(&self) -> Result<(), Box<dyn Error>> {
+        self.tx.execute(&format!("CREATE TABLE IF NOT EXISTS {} (datetime TEXT, syslog_host TEXT, syslog_program TEXT, syslog_pid INTEGER, msg_type TEXT, ip_addr TEXT, server_ip TEXT, mac_addr TEXT, duid TEXT, friendly_name TEXT, lease_time INTEGER, via TEXT, reason TEXT, vendor TEXT, locally_administered INTEGER);", self.tables.dhcp_logs), &[])?;
+        output::add_columns(&self.tx, &self.tables.dhcp_logs, &[("vendor", "TEXT"), ("locally_administered", "INTEGER")])?;
+        self.tx.execute(&format!("CREATE TABLE IF NOT EXISTS {} (mac_addr TEXT PRIMARY KEY, first_seen TEXT, last_seen TEXT, leases INTEGER, vendor TEXT, locally_administered INTEGER);", self.tables.devices), &[])?;
+        output::add_columns(&self.tx, &self.tables.devices, &[("vendor", "TEXT"), ("locally_administered", "INTEGER")])?;
+        self.tx.execute(&format!("CREATE TABLE IF NOT EXISTS {} (mac_addr TEXT, hostname TEXT, first_seen TEXT, last_seen TEXT, PRIMARY KEY (mac_addr, hostname));", self.tables.device_hostnames), &[])?;
+        self.tx.execute(&format!("CREATE TABLE IF NOT EXISTS {} (mac_addr TEXT, ip_addr TEXT, first_seen TEXT, last_seen TEXT, PRIMARY KEY (mac_addr, ip_addr));", self.tables.device_ips), &[])?;
         Ok(())
     }
 
-    fn insert_log_entry(&mut self, log_entry: &LogEntry) -> Result<(), Box<Error>> {
-        if let LogEntry{ datetime, msg: DhcpMsg::Ack{ip_addr, mac_addr, friendly_name} } = log_entry {
-            self.tx.execute("INSERT INTO dhcp_logs (datetime, ip_addr, mac_addr) VALUES (?, ?, ?)", &[datetime, &ip_addr.as_str(), &mac_addr.as_str()])?;
+    /// Reads back the devices tables written by earlier runs.
+    fn load_inventory(&self) -> Result<Inventory, Box<dyn Error>> {
+        fn seen(first: DateTime<Utc>, last: DateTime<Utc>) -> Seen {
+            Seen { first: first.into(), last: last.into() }
+        }
+        let mut devices = BTreeMap::new();
+        let mut stmt = self.tx.prepare(&format!("SELECT mac_addr, first_seen, last_seen, leases FROM {}", self.tables.devices))?;
+        for row in stmt.query_map(&[], |row| (row.get::<_, String>(0), seen(row.get(1), row.get(2)), row.get::<_, u32>(3)))? {
+            let (mac_addr, seen, leases) = row?;
+            let mac_addr: MacAddr = mac_addr.parse()?;
+            let mut device = Device::new(mac_addr, seen.first);
+            device.seen = seen;
+            device.leases = leases;
+            devices.insert(mac_addr, device);
+        }
+        let mut stmt = self.tx.prepare(&format!("SELECT mac_addr, hostname, first_seen, last_seen FROM {}", self.tables.device_hostnames))?;
+        for row in stmt.query_map(&[], |row| (row.get::<_, String>(0), row.get::<_, String>(1), seen(row.get(2), row.get(3))))? {
+            let (mac_addr, hostname, seen) = row?;
+            if let Some(device) = devices.get_mut(&mac_addr.parse()?) {
+                device.hostnames.insert(hostname, seen);
+            }
+        }
+        let mut stmt = self.tx.prepare(&format!("SELECT mac_addr, ip_addr, first_seen, last_seen FROM {}", self.tables.device_ips))?;
+        for row in stmt.query_map(&[], |row| (row.get::<_, String>(0), row.get::<_, String>(1), seen(row.get(2), row.get(3))))? {
+            let (mac_addr, ip_addr, seen) = row?;
+            if let Some(device) = devices.get_mut(&mac_addr.parse()?) {
+                device.ip_addrs.insert(ip_addr.parse::<IpAddr>()?, seen);
+            }
+        }
+        let mut inventory = Inventory::new();
+        for device in devices.into_values() {
+            inventory.insert(device);
+        }
+        Ok(inventory)
+    }
+
+    /// Replaces the contents of the devices tables with `inventory`.
+    /// Writes `devices` over what the tables held for them. Devices only
+    /// ever gain hostnames and addresses, so nothing needs deleting.
+    fn save_devices(&mut self, devices: &[&Device]) -> Result<(), Box<dyn Error>> {
+        for device in devices {
+            let mac_addr = device.mac_addr.to_string();
+            self.tx.execute(
+                &format!("INSERT OR REPLACE INTO {} (mac_addr, first_seen, last_seen, leases, vendor, locally_administered) VALUES (?, ?, ?, ?, ?, ?)", self.tables.devices),
+                &[&mac_addr, &device.seen.first, &device.seen.last, &device.leases, &device.mac_addr.vendor(), &device.mac_addr.is_locally_administered()])?;
+            for (hostname, seen) in &device.hostnames {
+                self.tx.execute(
+                    &format!("INSERT OR REPLACE INTO {} (mac_addr, hostname, first_seen, last_seen) VALUES (?, ?, ?, ?)", self.tables.device_hostnames),
+                    &[&mac_addr, hostname, &seen.first, &seen.last])?;
+            }
+            for (ip_addr, seen) in &device.ip_addrs {
+                self.tx.execute(
+                    &format!("INSERT OR REPLACE INTO {} (mac_addr, ip_addr, first_seen, last_seen) VALUES (?, ?, ?, ?)", self.tables.device_ips),
+                    &[&mac_addr, &ip_addr.to_string(), &seen.first, &seen.last])?;
+            }
         }
-        return Ok(())
+        Ok(())
+    }
+
+    fn insert_log_entry(&mut self, log_entry: &LogEntry) -> Result<(), Box<dyn Error>> {
+        let LogEntry{ datetime, header, msg } = log_entry;
+        let (ip_addr, server_ip, mac_addr, friendly_name, via, reason) = match msg {
+            DhcpMsg::Inform{ip_addr, via, reason} => (Some(ip_addr), None, None, None, via, reason),
+            DhcpMsg::Offer{ip_addr, mac_addr, friendly_name, via, reason} => (Some(ip_addr), None, Some(mac_addr), friendly_name.as_ref(), via, reason),
+            DhcpMsg::Ack{ip_addr, mac_addr, friendly_name, via, reason, ..} => (Some(ip_addr), None, Some(mac_addr), friendly_name.as_ref(), via, reason),
+            DhcpMsg::Nak{ip_addr, mac_addr, via, reason} => (Some(ip_addr), None, Some(mac_addr), None, via, reason),
+            DhcpMsg::Request{ip_addr, server_ip, mac_addr, friendly_name, via, reason} => (Some(ip_addr), server_ip.as_ref(), Some(mac_addr), friendly_name.as_ref(), via, reason),
+            DhcpMsg::Discover{mac_addr, friendly_name, via, reason} => (None, None, Some(mac_addr), friendly_name.as_ref(), via, reason),
+            DhcpMsg::Release{ip_addr, mac_addr, via, reason} => (Some(ip_addr), None, Some(mac_addr), None, via, reason),
+            DhcpMsg::Decline{ip_addr, mac_addr, via, reason} => (Some(ip_addr), None, Some(mac_addr), None, via, reason),
+            DhcpMsg::Expire{ip_addr, mac_addr, via, reason} => (Some(ip_addr), None, Some(mac_addr), None, via, reason),
+        };
+        let lease_time = match msg {
+            DhcpMsg::Ack{lease_time, ..} => *lease_time,
+            _ => None,
+        };
+        let (vendor, locally_administered) = (mac_addr.and_then(MacAddr::vendor), mac_addr.map(MacAddr::is_locally_administered));
+        let (ip_addr, server_ip, mac_addr, friendly_name, via, reason) = (
+            ip_addr.map(Ipv4Addr::to_string),
+            server_ip.map(Ipv4Addr::to_string),
+            mac_addr.map(MacAddr::to_string),
+            friendly_name.map(String::as_str),
+            via.as_ref().map(String::as_str),
+            reason.as_ref().map(String::as_str));
+        self.tx.execute(
+            &format!("INSERT INTO {} (datetime, syslog_host, syslog_program, syslog_pid, msg_type, ip_addr, server_ip, mac_addr, friendly_name, lease_time, via, reason, vendor, locally_administered) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", self.tables.dhcp_logs),
+            &[datetime, &header.host.as_str(), &header.program.as_str(), &header.pid, &msg.msg_type(), &ip_addr, &server_ip, &mac_addr, &friendly_name, &lease_time, &via, &reason, &vendor, &locally_administered])?;
+        Ok(())
+    }
+
+    fn insert_dhcp6_log_entry(&mut self, log_entry: &dhcp6::LogEntry) -> Result<(), Box<dyn Error>> {
+        let dhcp6::LogEntry{ datetime, header, msg } = log_entry;
+        let ip_addr = msg.ip_addr.or(msg.link_addr).map(|ip_addr| ip_addr.to_string());
+        let mac_addr = msg.duid.as_ref().and_then(dhcp6::Duid::mac_addr);
+        let (vendor, locally_administered) = (mac_addr.and_then(|mac_addr| mac_addr.vendor()), mac_addr.map(|mac_addr| mac_addr.is_locally_administered()));
+        let mac_addr = mac_addr.map(|mac_addr| mac_addr.to_string());
+        let duid = msg.duid.as_ref().map(dhcp6::Duid::to_string);
+        let (friendly_name, via) = (msg.hostname.as_deref(), msg.interface.as_deref());
+        self.tx.execute(
+            &format!("INSERT INTO {} (datetime, syslog_host, syslog_program, syslog_pid, msg_type, ip_addr, mac_addr, duid, friendly_name, lease_time, via, vendor, locally_administered) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", self.tables.dhcp_logs),
+            &[datetime, &header.host.as_str(), &header.program.as_str(), &header.pid, &msg.msg_type.name(), &ip_addr, &mac_addr, &duid, &friendly_name, &msg.lease_time, &via, &vendor, &locally_administered])?;
+        Ok(())
     }
 
-    fn commit(self) -> Result<(), Box<Error>> {
+    fn commit(self) -> Result<(), Box<dyn Error>> {
         self.tx.commit()?;
         Ok(())
     }
 }
 
-fn run() -> Result<(), Box<Error>> {
+/// Opens the files under `paths` that aren't among `followed`, from where
+/// `ledger` left off. A log that was rotated while it is still followed under
+/// its old name is left until that name moves on to the new log.
+fn open_new_files(ledger: &Ledger, paths: &[PathBuf], filter: &FileFilter, timezone: Tz, followed: &[(LogFile, TimestampOptions)]) -> Result<Vec<(LogFile, TimestampOptions)>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    for path in paths {
+        for filename in filter.files(path)? {
+            if followed.iter().any(|(file, _)| file.is_same_file(&filename)) {
+                continue;
+            }
+            let file = ledger.open(filename)?;
+            let timestamp_options = TimestampOptions::with_reference(timezone, DateTime::<Utc>::from(file.modified()?));
+            files.push((file, timestamp_options));
+        }
+    }
+    Ok(files)
+}
+
+/// Inserts the lines of `file` that haven't been read yet, returning how
+/// many entries were added.
+fn ingest_file(tx: &mut Tx, file: &mut LogFile, timestamp_options: &mut TimestampOptions, inventory: &mut Inventory, failures: &mut File, failure_stages: &mut BTreeMap<ParseStage, usize>) -> Result<usize, Box<dyn Error>> {
+    let mut entries = 0;
+    while let Some(line) = file.read_line()? {
+        match LogEntry::with_options(&line, timestamp_options) {
+            Ok(log_entry) => {
+                entries += 1;
+                tx.insert_log_entry(&log_entry)?;
+                inventory.add_dhcp_log_entry(&log_entry);
+            },
+            Err(e) => match dhcp6::LogEntry::with_options(&line, timestamp_options) {
+                Ok(log_entry) => {
+                    entries += 1;
+                    tx.insert_dhcp6_log_entry(&log_entry)?;
+                    inventory.add_dhcp6_log_entry(&log_entry);
+                },
+                Err(e6) => {
+                    // Report whichever parser got further into the line.
+                    let e = if e6.offset > e.offset { e6 } else { e };
+                    failures.write_all(&line)?;
+                    failures.write_all(&b"\n"[..])?;
+                    eprintln!("Failed to parse line: {}: {}", e, String::from_utf8_lossy(&line));
+                    *failure_stages.entry(e.stage).or_insert(0) += 1;
+                },
+            },
+        }
+    }
+    file.save(&tx.tx)?;
+    Ok(entries)
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
     let opt = Opt::from_args();
     println!("{:?}", opt);
-    let mut db = rusqlite::Connection::open("output.db")?;
-    let mut tx = Tx::new(&mut db)?;
+    let file_config = match opt.config {
+        Some(ref filename) => Config::load(filename)?,
+        None => Config::default(),
+    };
+    let config = Config { database: opt.output.clone(), table_prefix: opt.table_prefix.clone(), mode: opt.mode, failures_log: opt.failures_log.clone() }.or(file_config);
+    let tables = Tables::new(&config)?;
+    let mut db = rusqlite::Connection::open(config.database())?;
+    for table in &tables.all() {
+        output::prepare_table(&db, config.mode(), table)?;
+    }
+    let filter = FileFilter::new(&opt.include, &opt.exclude, opt.sort)?;
+    let (mut files, mut inventory) = {
+        let tx = Tx::new(&mut db, &tables)?;
+        tx.create_tables()?;
+        let inventory = tx.load_inventory()?;
+        let ledger = Ledger::load(&tx.tx, &tables.dhcp_logs)?;
+        let files = open_new_files(&ledger, &opt.files, &filter, opt.timezone, &[])?;
+        tx.commit()?;
+        (files, inventory)
+    };
+    let mut failures = config.open_failures_log()?;
     let mut total_entries = 0;
-    for filename in opt.files {
-        let mut file_entries = 0;
-        let filereader = BufReader::new(File::open(&filename)?);
-        for line in filereader.split(b'\n') {
-            let line = line?;
-            match LogEntry::new(&line) {
-                Ok(log_entry) => {
-                    total_entries += 1;
-                    file_entries += 1;
-                    tx.insert_log_entry(&log_entry)?;
-                },
-                Err(_) => eprintln!("Failed to parse line: {}", String::from_utf8_lossy(&line)),
+    let mut failure_stages: BTreeMap<ParseStage, usize> = BTreeMap::new();
+    loop {
+        let mut tx = Tx::new(&mut db, &tables)?;
+        let mut cycle_entries = 0;
+        for &mut (ref mut file, ref mut timestamp_options) in files.iter_mut() {
+            // Check for rotation before reading, so that the old file is
+            // finished before switching to the new one.
+            let rotated = opt.follow && file.rotated()?;
+            let mut file_entries = ingest_file(&mut tx, file, timestamp_options, &mut inventory, &mut failures, &mut failure_stages)?;
+            if rotated {
+                *file = file.reopen()?;
+                file_entries += ingest_file(&mut tx, file, timestamp_options, &mut inventory, &mut failures, &mut failure_stages)?;
             }
+            if file_entries > 0 || !opt.follow {
+                println!("Added {} entries from file: {}", file_entries, file.path().to_string_lossy());
+            }
+            cycle_entries += file_entries;
+        }
+        if opt.follow {
+            // Only after the followed files are finished, so that a rotated
+            // log picks up from where they left off.
+            let ledger = Ledger::load(&tx.tx, &tables.dhcp_logs)?;
+            for (mut file, mut timestamp_options) in open_new_files(&ledger, &opt.files, &filter, opt.timezone, &files)? {
+                let file_entries = ingest_file(&mut tx, &mut file, &mut timestamp_options, &mut inventory, &mut failures, &mut failure_stages)?;
+                println!("Added {} entries from new file: {}", file_entries, file.path().to_string_lossy());
+                cycle_entries += file_entries;
+                files.push((file, timestamp_options));
+            }
+        }
+        tx.save_devices(&inventory.take_changed())?;
+        total_entries += cycle_entries;
+        tx.commit()?;
+        if !opt.follow {
+            break;
+        }
+        // Following never ends, so report each poll's failures as it goes.
+        for (stage, count) in ::std::mem::take(&mut failure_stages) {
+            println!("Failed to parse {} lines at {}", count, stage);
+        }
+        thread::sleep(Duration::from_secs(opt.poll_interval));
+        // From now on the files are followed, so new lines are from around
+        // now rather than from before the files were last modified.
+        for &mut (_, ref mut timestamp_options) in files.iter_mut() {
+            timestamp_options.refresh_reference();
         }
-        println!("Added {} entries from file: {}", file_entries, filename.to_string_lossy());
     }
-    tx.commit()?;
     println!("Added {} total entries", total_entries);
+    for (stage, count) in failure_stages {
+        println!("Failed to parse {} lines at {}", count, stage);
+    }
     Ok(())
 }
 
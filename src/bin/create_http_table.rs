@@ -1,58 +1,265 @@
 extern crate parse_logs;
-#[macro_use]
 extern crate structopt;
 extern crate rusqlite;
 extern crate chrono;
-extern crate phf;
-
-include!(concat!(env!("OUT_DIR"), "/friendly_names.rs"));
+extern crate chrono_tz;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate ctrlc;
 
 use structopt::StructOpt;
 use std::error::Error;
 use std::path::{Path, PathBuf};
-use std::io::BufReader;
-use std::fs::File;
-use std::io::BufRead;
-use parse_logs::{http, dhcp};
+use std::io::{self, BufReader, BufWriter};
+use std::fs::{self, File};
+use std::io::Read;
+use parse_logs::{http, decompress, dhcp, dhcp6, leases, MacAddr, ParseStage, TimestampOptions};
+use parse_logs::correlate::{DeviceIdentities, DeviceLinker, IpToMacBuilder, IpToMacLookup};
+use parse_logs::filter::{DeviceFilter, FilterConfig, Rules};
+use parse_logs::ledger::{FileProgress, Ledger, LogFile};
+use parse_logs::output::{self, Config, Mode};
+use parse_logs::walk::{FileFilter, SortBy};
 use std::borrow::Cow;
 use std::collections::BTreeSet;
-use rusqlite::types::ToSql;
+use rusqlite::types::{ToSql, Value};
 use std::io::Write;
-use std::collections::HashMap;
-use std::fs;
-use chrono::NaiveDateTime;
+use std::collections::{BTreeMap, HashMap};
+use chrono::{DateTime, Duration, Utc};
+use std::sync::mpsc;
+use std::time;
+use chrono_tz::Tz;
 
 #[derive(StructOpt, Debug)]
 struct Opt {
     #[structopt(long = "dhcp_dir", parse(from_os_str))]
-    dhcp_dir: PathBuf,
+    dhcp_dir: Option<PathBuf>,
+
+    /// ISC dhcpd lease files to read alongside, or instead of, the DHCP logs.
+    #[structopt(long = "dhcp_leases", parse(from_os_str))]
+    dhcp_leases: Vec<PathBuf>,
 
     #[structopt(long = "http_dir", parse(from_os_str))]
     http_dir: PathBuf,
+
+    /// Timezone of log timestamps that carry no UTC offset.
+    #[structopt(long = "timezone", default_value = "UTC")]
+    timezone: Tz,
+
+    /// Lease time, in seconds, assumed for DHCP acknowledgements that don't
+    /// log one. Traffic from an address after its lease expires isn't
+    /// attributed to any device.
+    #[structopt(long = "default_lease_time", default_value = "86400")]
+    default_lease_time: i64,
+
+    /// File to load the IP to MAC state from and save it to, so that later
+    /// runs only read DHCP logs that are new or have changed.
+    #[structopt(long = "ip_to_mac_state", parse(from_os_str))]
+    ip_to_mac_state: Option<PathBuf>,
+
+    /// Keep running and insert HTTP lines as they are appended to the logs,
+    /// following them across rotation like `tail -F`. New DHCP log lines
+    /// update the IP to MAC mapping as they arrive. Directories are walked
+    /// again on every check, so logs created in them are read too.
+    #[structopt(long = "follow")]
+    follow: bool,
+
+    /// Seconds to wait between checks for new lines when following.
+    #[structopt(long = "poll_interval", default_value = "1")]
+    poll_interval: u64,
+
+    /// Seconds to wait between saves of --ip_to_mac_state when following.
+    /// It is also saved when following is stopped with Ctrl-C.
+    #[structopt(long = "save_interval", default_value = "60")]
+    save_interval: u64,
+
+    /// Glob patterns, relative to --dhcp_dir and --http_dir, of the files to
+    /// read from it. All files are read when there are none.
+    #[structopt(long = "include")]
+    include: Vec<String>,
+
+    /// Glob patterns of files to leave out when reading directories.
+    #[structopt(long = "exclude")]
+    exclude: Vec<String>,
+
+    /// The order to read the files in a directory in: name or mtime.
+    #[structopt(long = "sort", default_value = "name")]
+    sort: SortBy,
+
+    /// JSON file of output settings, with any of the keys database,
+    /// table_prefix, mode and failures_log. Flags override it.
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// SQLite database to write to. Defaults to output.db.
+    #[structopt(long = "output", parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Prefix for the names of the tables written, so that several sites
+    /// can share a database.
+    #[structopt(long = "table_prefix")]
+    table_prefix: Option<String>,
+
+    /// What to do with tables that already exist: create, append or
+    /// replace. Defaults to append.
+    #[structopt(long = "mode")]
+    mode: Option<Mode>,
+
+    /// File to append the HTTP and DHCP lines that fail to parse to, which replace
+    /// mode empties first. Defaults to failures.log.
+    #[structopt(long = "failures_log", parse(from_os_str))]
+    failures_log: Option<PathBuf>,
+
+    /// JSON file of the devices to keep traffic from, with allow and deny
+    /// rules that each list hostnames (globs), hostname_regexes, macs
+    /// (addresses, or prefixes such as an OUI) and vendors (globs). All
+    /// traffic is kept when there are no rules.
+    #[structopt(long = "device_filter", parse(from_os_str))]
+    device_filter: Option<PathBuf>,
+
+    /// Hostname glob of devices to keep traffic from, added to the allow
+    /// rules.
+    #[structopt(long = "allow_host")]
+    allow_host: Vec<String>,
+
+    /// Hostname glob of devices to drop traffic from, added to the deny
+    /// rules.
+    #[structopt(long = "deny_host")]
+    deny_host: Vec<String>,
+
+    /// MAC address, or prefix of one, of devices to keep traffic from.
+    #[structopt(long = "allow_mac")]
+    allow_mac: Vec<String>,
+
+    /// MAC address, or prefix of one, of devices to drop traffic from.
+    #[structopt(long = "deny_mac")]
+    deny_mac: Vec<String>,
+
+    /// Vendor glob, such as "Raspberry Pi*", of devices to keep traffic
+    /// from.
+    #[structopt(long = "allow_vendor")]
+    allow_vendor: Vec<String>,
+
+    /// Vendor glob of devices to drop traffic from.
+    #[structopt(long = "deny_vendor")]
+    deny_vendor: Vec<String>,
+
+    /// Attribute traffic from the locally administered MAC addresses that
+    /// phones make up per network to the device that presented the same
+    /// hostname or DHCP client identifier from another address. The address
+    /// seen is kept in the linked_mac_addr column.
+    #[structopt(long = "link_private_macs")]
+    link_private_macs: bool,
+
+    /// Keep traffic from addresses that no DHCP log or lease ties to a MAC
+    /// address, which is dropped otherwise.
+    #[structopt(long = "keep_unknown_devices")]
+    keep_unknown_devices: bool,
 }
 
-struct Tx<'a>{
-    tx: rusqlite::Transaction<'a>,
+/// What has been learned from the DHCP logs, saved between runs.
+#[derive(Serialize, Deserialize)]
+struct DhcpState {
+    ip_to_mac: IpToMacBuilder,
+    mac_to_friendly_name: HashMap<MacAddr, String>,
+    #[serde(default)]
+    links: DeviceLinker,
+    /// How far each DHCP log has been read.
+    #[serde(default)]
+    dhcp_logs: Vec<FileProgress>,
+}
+
+impl DhcpState {
+    fn new(default_lease_time: Duration) -> Self {
+        DhcpState { ip_to_mac: IpToMacBuilder::new(default_lease_time), mac_to_friendly_name: HashMap::new(), links: DeviceLinker::new(), dhcp_logs: Vec::new() }
+    }
+
+    fn load<P: AsRef<Path>>(filename: P) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(filename)?))?)
+    }
+
+    /// Writes the state next to `filename` and then renames it into place,
+    /// so an interrupted save leaves the previous state intact.
+    fn save<P: AsRef<Path>>(&self, filename: P) -> Result<(), Box<dyn Error>> {
+        let filename = filename.as_ref();
+        let mut tmp_name = filename.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let mut file = BufWriter::new(File::create(&tmp_name)?);
+        serde_json::to_writer(&mut file, self)?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_name, filename)?;
+        Ok(())
+    }
+
+    /// Records how far `file` has been read, replacing what was recorded
+    /// for it before.
+    fn record_progress(&mut self, file: &LogFile) {
+        let progress = file.progress();
+        match self.dhcp_logs.iter_mut().find(|recorded| recorded.path == progress.path && recorded.inode == progress.inode) {
+            Some(recorded) => *recorded = progress,
+            None => self.dhcp_logs.push(progress),
+        }
+    }
+}
+
+/// Columns for the well known proxy attributes, named after them. Durations
+/// are in microseconds, as logged.
+const REQUEST_COLUMNS: &[(&str, &str)] = &[
+    ("srcip", "TEXT"), ("dstip", "TEXT"), ("method", "TEXT"), ("url", "TEXT"),
+    ("statuscode", "INTEGER"), ("size", "INTEGER"), ("action", "TEXT"), ("categoryname", "TEXT"), ("ua", "TEXT"),
+    ("authtime", "INTEGER"), ("dnstime", "INTEGER"), ("cattime", "INTEGER"), ("avscantime", "INTEGER"), ("fullreqtime", "INTEGER"),
+];
+
+/// The table written to and the columns it has, kept across transactions.
+struct Table {
+    name: String,
     cols: Vec<String>,
     cols_set: BTreeSet<String>,
 }
 
+impl Table {
+    fn new(name: String) -> Self {
+        Table{name, cols: Vec::new(), cols_set: BTreeSet::new()}
+    }
+}
+
+struct Tx<'a>{
+    tx: rusqlite::Transaction<'a>,
+    table: &'a mut Table,
+}
+
 impl<'a> Tx<'a> {
-    fn new(db: &mut rusqlite::Connection) -> Result<Tx, Box<Error>> {
-        let mut tx = Tx{tx: db.transaction()?, cols: Vec::new(), cols_set: BTreeSet::new()};
-        tx.create_table()?;
-        Ok(tx)
+    fn new(db: &'a mut rusqlite::Connection, table: &'a mut Table) -> Result<Tx<'a>, Box<dyn Error>> {
+        Ok(Tx{tx: db.transaction()?, table})
     }
 
-    fn create_table(&mut self) -> Result<(), Box<Error>> {
-        self.tx.execute("CREATE TABLE http_logs (datetime TEXT, mac_addr TEXT, friendly_name TEXT);", &[])?;
-        self.cols.push("datetime".to_string());
-        self.cols.push("mac_addr".to_string());
-        self.cols.push("friendly_name".to_string());
+    /// Creates or updates the table, once before the first insert.
+    fn create_table(&mut self) -> Result<(), Box<dyn Error>> {
+        let request_cols: Vec<String> = REQUEST_COLUMNS.iter().map(|&(name, sql_type)| format!(", {} {}", name, sql_type)).collect();
+        self.tx.execute(&format!("CREATE TABLE IF NOT EXISTS {} (datetime TEXT, syslog_host TEXT, syslog_program TEXT, syslog_pid INTEGER, mac_addr TEXT, friendly_name TEXT, vendor TEXT, locally_administered INTEGER, linked_mac_addr TEXT{});", self.table.name, request_cols.concat()), &[])?;
+        output::add_columns(&self.tx, &self.table.name, &[("vendor", "TEXT"), ("locally_administered", "INTEGER"), ("linked_mac_addr", "TEXT")])?;
+        output::add_columns(&self.tx, &self.table.name, REQUEST_COLUMNS)?;
+        // Pick up the attribute columns added by earlier runs.
+        let existing: Vec<String> = {
+            let mut stmt = self.tx.prepare(&format!("PRAGMA table_info({})", self.table.name))?;
+            let cols = stmt.query_map(&[], |row| row.get(1))?.collect::<Result<_, _>>()?;
+            cols
+        };
+        self.table.cols_set.extend(existing);
+        self.table.cols.push("datetime".to_string());
+        self.table.cols.push("syslog_host".to_string());
+        self.table.cols.push("syslog_program".to_string());
+        self.table.cols.push("syslog_pid".to_string());
+        self.table.cols.push("mac_addr".to_string());
+        self.table.cols.push("friendly_name".to_string());
+        self.table.cols.push("vendor".to_string());
+        self.table.cols.push("locally_administered".to_string());
+        self.table.cols.push("linked_mac_addr".to_string());
+        self.table.cols.extend(REQUEST_COLUMNS.iter().map(|&(name, _)| name.to_string()));
         Ok(())
     }
 
-    fn sanitize_col_name(col: &str) -> Cow<str> {
+    fn sanitize_col_name(col: &str) -> Cow<'_, str> {
         match col {
             "group" => Cow::Owned("_group".to_string()),
             col if col.contains("-") => Cow::Owned(col.replace("-", "_")),
@@ -60,156 +267,401 @@ impl<'a> Tx<'a> {
         }
     }
 
-    fn add_col(&mut self, col: &str) -> Result<(), Box<Error>> {
+    fn add_col(&mut self, col: &str) -> Result<(), Box<dyn Error>> {
         let sanitized_col = Self::sanitize_col_name(col);
-        self.tx.execute(&format!("ALTER TABLE http_logs ADD {} TEXT", sanitized_col), &[])?;
-        self.cols.push(col.to_string());
-        self.cols_set.insert(col.to_string());
+        self.tx.execute(&format!("ALTER TABLE {} ADD {} TEXT", self.table.name, sanitized_col), &[])?;
+        self.table.cols.push(col.to_string());
+        self.table.cols_set.insert(sanitized_col.into_owned());
         Ok(())
     }
 
-    fn insert_log_entry(&mut self, mac_addr: Option<&str>, friendly_name: Option<&str>, log_entry: &http::LogEntry) -> Result<(), Box<Error>> {
-        let cols_required: BTreeSet<String> = log_entry.attrs.keys().cloned().collect();
-        let cols_to_add: Vec<String> = cols_required.difference(&self.cols_set).cloned().collect();
+    fn insert_request(&mut self, device: &Device, request: &http::HttpRequest) -> Result<(), Box<dyn Error>> {
+        // Well known attributes that didn't parse are left in `attrs`, and are
+        // kept as they were in their own columns.
+        let attrs: Vec<(&String, &Vec<u8>)> = request.attrs.iter().filter(|&(k, _)| !REQUEST_COLUMNS.iter().any(|&(name, _)| name == k)).collect();
+        let cols_required: BTreeSet<String> = attrs.iter().map(|&(k, _)| Self::sanitize_col_name(k).into_owned()).collect();
+        let cols_to_add: Vec<String> = cols_required.difference(&self.table.cols_set).cloned().collect();
         for col in cols_to_add {
             self.add_col(&col)?;
         }
-        let log_datetime = log_entry.datetime;
-        let (mut entry_cols, entry_values): (Vec<String>, Vec<Vec<u8>>) = log_entry.attrs.iter().map(|(k,v)| (Self::sanitize_col_name(k).into(), v.clone())).unzip();
-        let entry_values: Vec<rusqlite::types::Value> = entry_values.into_iter().map(|v| rusqlite::types::Value::Blob(v)).collect();
-        let mut entry_values_traits: Vec<&ToSql> = entry_values.iter().map(|v| v as &ToSql).collect();
+        let log_datetime = request.datetime;
+        let header = &request.header;
+        let (mac_addr, friendly_name) = (device.mac_addr, device.friendly_name.as_deref());
+        let (vendor, locally_administered) = (mac_addr.and_then(|mac_addr| mac_addr.vendor()), mac_addr.map(|mac_addr| mac_addr.is_locally_administered()));
+        let mac_addr = mac_addr.map(|mac_addr| mac_addr.to_string());
+        let linked_mac_addr = device.linked_mac_addr.map(|mac_addr| mac_addr.to_string());
+        let text = |value: &Option<String>| value.clone().map(Value::Text);
+        let micros = |duration: Option<Duration>| duration.and_then(|duration| duration.num_microseconds()).map(Value::Integer);
+        let request_values: Vec<Value> = vec![
+            request.src_ip.map(|ip| Value::Text(ip.to_string())), request.dst_ip.map(|ip| Value::Text(ip.to_string())), text(&request.method), text(&request.url),
+            request.status.map(|status| Value::Integer(status.into())), request.size.map(|size| Value::Integer(size as i64)), text(&request.action), text(&request.category), text(&request.user_agent),
+            micros(request.auth_time), micros(request.dns_time), micros(request.category_time), micros(request.av_scan_time), micros(request.full_request_time),
+        ].into_iter().zip(REQUEST_COLUMNS).map(|(value, &(name, _))| {
+            value.or_else(|| request.attrs.get(name).cloned().map(Value::Blob)).unwrap_or(Value::Null)
+        }).collect();
+        let (host, program) = (header.host.as_str(), header.program.as_str());
+        let (mut entry_cols, entry_values): (Vec<String>, Vec<Vec<u8>>) = attrs.iter().map(|&(k,v)| (Self::sanitize_col_name(k).into(), v.clone())).unzip();
+        let entry_values: Vec<Value> = entry_values.into_iter().map(Value::Blob).collect();
+        let mut entry_values_traits: Vec<&dyn ToSql> = entry_values.iter().map(|v| v as &dyn ToSql).collect();
         entry_cols.push("datetime".to_string());
+        entry_cols.push("syslog_host".to_string());
+        entry_cols.push("syslog_program".to_string());
+        entry_cols.push("syslog_pid".to_string());
         entry_cols.push("mac_addr".to_string());
         entry_cols.push("friendly_name".to_string());
+        entry_cols.push("vendor".to_string());
+        entry_cols.push("locally_administered".to_string());
+        entry_cols.push("linked_mac_addr".to_string());
         entry_values_traits.push(&log_datetime);
+        entry_values_traits.push(&host);
+        entry_values_traits.push(&program);
+        entry_values_traits.push(&header.pid);
         entry_values_traits.push(&mac_addr);
         entry_values_traits.push(&friendly_name);
-        let insert_stmt = format!("INSERT INTO http_logs ({}) VALUES ({})",
+        entry_values_traits.push(&vendor);
+        entry_values_traits.push(&locally_administered);
+        entry_values_traits.push(&linked_mac_addr);
+        entry_cols.extend(REQUEST_COLUMNS.iter().map(|&(name, _)| name.to_string()));
+        entry_values_traits.extend(request_values.iter().map(|v| v as &dyn ToSql));
+        let insert_stmt = format!("INSERT INTO {} ({}) VALUES ({})",
+                self.table.name,
                 entry_cols.join(","),
                 entry_cols.iter().map(|_| "?".to_string()).collect::<Vec<_>>().join(","));
         self.tx.execute(&insert_stmt, entry_values_traits.as_slice())?;
         Ok(())
     }
 
-    fn commit(self) -> Result<(), Box<Error>> {
+    fn commit(self) -> Result<(), Box<dyn Error>> {
         self.tx.commit()?;
         Ok(())
     }
 }
 
-#[derive(Debug)]
-struct IpToMacBuilder(HashMap<String, Vec<(NaiveDateTime, String)>>);
-#[derive(Debug)]
-struct IpToMacLookup(HashMap<String, Vec<(NaiveDateTime, String)>>);
+fn add_friendly_name(mac_to_friendly_name: &mut HashMap<MacAddr, String>, mac_addr: MacAddr, friendly_name: String) {
+    use std::collections::hash_map::Entry::*;
+    match mac_to_friendly_name.entry(mac_addr) {
+        Occupied(occupied) => {
+            if *occupied.get() != friendly_name {
+                eprintln!("mac {} has multiple friendly names: ({}, {})", mac_addr, occupied.get(), friendly_name);
+            }
+        },
+        Vacant(vacant) => {
+            vacant.insert(friendly_name);
+        },
+    }
+}
+
+/// Adds what one DHCP log line says to `state`, returning whether it parsed.
+/// Lines that don't are written to `failures`.
+fn add_dhcp_line(line: &[u8], timestamp_options: &mut TimestampOptions, state: &mut DhcpState, failures: &mut File) -> io::Result<bool> {
+    let DhcpState { ip_to_mac, mac_to_friendly_name, links, .. } = state;
+    match dhcp::LogEntry::with_options(line, timestamp_options) {
+        Ok(log_entry) => {
+            if let dhcp::DhcpMsg::Ack{mac_addr, friendly_name: Some(ref friendly_name), ..} = log_entry.msg {
+                add_friendly_name(mac_to_friendly_name, mac_addr, friendly_name.clone());
+            }
+            ip_to_mac.add_dhcp_log_entry(&log_entry);
+            links.add_dhcp_log_entry(&log_entry);
+            Ok(true)
+        },
+        Err(e) => match dhcp6::LogEntry::with_options(line, timestamp_options) {
+            Ok(log_entry) => {
+                if let dhcp6::Dhcp6Msg{ msg_type: dhcp6::MsgType::Reply, duid: Some(ref duid), hostname: Some(ref hostname), .. } = log_entry.msg {
+                    if let Some(mac_addr) = duid.mac_addr() {
+                        add_friendly_name(mac_to_friendly_name, mac_addr, hostname.clone());
+                    }
+                }
+                ip_to_mac.add_dhcp6_log_entry(&log_entry);
+                Ok(true)
+            },
+            Err(e6) => {
+                let e = if e6.offset > e.offset { e6 } else { e };
+                failures.write_all(line)?;
+                failures.write_all(&b"\n"[..])?;
+                eprintln!("Failed to parse line: {}: {}", e, String::from_utf8_lossy(line));
+                Ok(false)
+            },
+        },
+    }
+}
+
+/// Opens the files under `path` that aren't among `followed`, from where
+/// `ledger` left off. A log that was rotated while it is still followed under
+/// its old name is left until that name moves on to the new log.
+fn open_new_files<P: AsRef<Path>>(ledger: &Ledger, path: P, filter: &FileFilter, timezone: Tz, followed: &[(LogFile, TimestampOptions)]) -> Result<Vec<(LogFile, TimestampOptions)>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    for filename in filter.files(path)? {
+        if followed.iter().any(|(file, _)| file.is_same_file(&filename)) {
+            continue;
+        }
+        let file = ledger.open(filename)?;
+        let timestamp_options = TimestampOptions::with_reference(timezone, DateTime::<Utc>::from(file.modified()?));
+        files.push((file, timestamp_options));
+    }
+    Ok(files)
+}
 
-impl IpToMacBuilder {
-    fn new() -> Self {
-        IpToMacBuilder(HashMap::new())
+/// Reads the DHCP logs in `dir` that aren't in `files` yet into `state`,
+/// from where `ledger` left off, and adds them to `files` for following.
+/// Returns whether there were any lines.
+fn read_new_dhcp_logs<P: AsRef<Path>>(dir: P, filter: &FileFilter, timezone: Tz, ledger: &Ledger, state: &mut DhcpState, failures: &mut File, files: &mut Vec<(LogFile, TimestampOptions)>) -> Result<bool, Box<dyn Error>> {
+    let mut changed = false;
+    for (mut file, mut timestamp_options) in open_new_files(ledger, dir, filter, timezone, files)? {
+        while let Some(line) = file.read_line()? {
+            changed |= add_dhcp_line(&line, &mut timestamp_options, state, failures)?;
+        }
+        state.record_progress(&file);
+        files.push((file, timestamp_options));
     }
+    Ok(changed)
+}
+
+/// Reads the lines of the DHCP logs in `dir` that `state` hasn't seen yet,
+/// returning the logs positioned at their ends for following.
+fn read_dhcp_logs<P: AsRef<Path>>(dir: P, filter: &FileFilter, timezone: Tz, state: &mut DhcpState, failures: &mut File) -> Result<Vec<(LogFile, TimestampOptions)>, Box<dyn Error>> {
+    let ledger = Ledger::from_progress(state.dhcp_logs.drain(..));
+    let mut files = Vec::new();
+    read_new_dhcp_logs(dir, filter, timezone, &ledger, state, failures, &mut files)?;
+    Ok(files)
+}
 
-    fn add_dhcp_ack(&mut self, date: NaiveDateTime, ip_addr: &str, mac_addr: &str) {
-        self.0.entry(ip_addr.to_string()).or_default().push((date, mac_addr.to_string()));
+/// Reads the lines appended to the followed DHCP logs in `dir` into
+/// `state`, and the logs created there since, returning whether there were
+/// any.
+fn follow_dhcp_logs(dir: &Path, filter: &FileFilter, timezone: Tz, files: &mut Vec<(LogFile, TimestampOptions)>, state: &mut DhcpState, failures: &mut File) -> Result<bool, Box<dyn Error>> {
+    let mut changed = false;
+    for &mut (ref mut file, ref mut timestamp_options) in files.iter_mut() {
+        let rotated = file.rotated()?;
+        timestamp_options.refresh_reference();
+        while let Some(line) = file.read_line()? {
+            changed |= add_dhcp_line(&line, timestamp_options, state, failures)?;
+        }
+        if rotated {
+            // Keep the progress of the rotated log, which is still read
+            // under its new name.
+            state.record_progress(file);
+            *file = file.reopen()?;
+            while let Some(line) = file.read_line()? {
+                changed |= add_dhcp_line(&line, timestamp_options, state, failures)?;
+            }
+        }
+        state.record_progress(file);
     }
+    // Only after the followed logs are finished, so that a rotated log picks
+    // up from where they left off.
+    let ledger = Ledger::from_progress(state.dhcp_logs.clone());
+    changed |= read_new_dhcp_logs(dir, filter, timezone, &ledger, state, failures, files)?;
+    Ok(changed)
+}
 
-    fn finalize(self) -> IpToMacLookup {
-        // Sort the (date, mac_addr) entries within each ip address, and then
-        // remove any consecutive entries that have the same mac address.
-        let finalized = self.0.into_iter().map(|(k, mut v)| {
-            v.sort_unstable();
-            let remove_dups = v.iter().take(1).cloned().chain(v.windows(2).filter_map(|window| {
-                if let &[(_, ref mac1), (ref date2, ref mac2)] = window {
-                    if mac1 == mac2 {
-                        None
-                    } else {
-                        Some((date2.clone(), mac2.clone()))
-                    }
-                } else {
-                    unreachable!();
+fn read_dhcp_leases<P: AsRef<Path>>(filename: P, state: &mut DhcpState) -> Result<(), Box<dyn Error>> {
+    let mut contents = Vec::new();
+    decompress::open(filename)?.read_to_end(&mut contents)?;
+    let leases = leases::parse(&contents)?;
+    for lease in &leases {
+        if let (Some(mac_addr), Some(hostname)) = (lease.mac_addr, &lease.hostname) {
+            add_friendly_name(&mut state.mac_to_friendly_name, mac_addr, hostname.clone());
+        }
+    }
+    state.links.add_leases(&leases);
+    state.ip_to_mac.add_leases(leases);
+    Ok(())
+}
+
+/// The device an HTTP entry came from.
+struct Device {
+    mac_addr: Option<MacAddr>,
+    /// The address the entry came from, when it was linked to `mac_addr`.
+    linked_mac_addr: Option<MacAddr>,
+    /// Lowercased.
+    friendly_name: Option<String>,
+}
+
+/// Works out which device HTTP entries came from.
+struct Attribution<'a> {
+    ip_to_mac: &'a IpToMacLookup,
+    mac_to_friendly_name: &'a HashMap<MacAddr, String>,
+    identities: &'a DeviceIdentities,
+    device_filter: &'a DeviceFilter,
+    keep_unknown_devices: bool,
+}
+
+impl<'a> Attribution<'a> {
+    /// The device `request` came from, or `None` if the device filter
+    /// leaves it out or its MAC address isn't known.
+    fn device(&self, request: &http::HttpRequest) -> Option<Device> {
+        let seen: Option<MacAddr> = request.src_ip.and_then(|ip| self.ip_to_mac.get_mac(request.datetime, ip));
+        if seen.is_none() && !self.keep_unknown_devices {
+            return None;
+        }
+        let mac_addr = seen.map(|seen| self.identities.identity(seen));
+        let linked_mac_addr = seen.filter(|&seen| Some(seen) != mac_addr);
+        let friendly_name: Option<String> = seen.into_iter().chain(mac_addr)
+            .filter_map(|mac_addr| self.mac_to_friendly_name.get(&mac_addr))
+            .next()
+            .map(|friendly_name| friendly_name.to_lowercase());
+        if self.device_filter.keep(mac_addr, friendly_name.as_deref()) {
+            Some(Device { mac_addr, linked_mac_addr, friendly_name })
+        } else {
+            None
+        }
+    }
+}
+
+/// Inserts the lines of `file` that haven't been read yet, returning how
+/// many entries were added.
+fn ingest_file(tx: &mut Tx, file: &mut LogFile, timestamp_options: &mut TimestampOptions, attribution: &Attribution, failures: &mut File, failure_stages: &mut BTreeMap<ParseStage, usize>) -> Result<usize, Box<dyn Error>> {
+    let mut entries = 0;
+    while let Some(line) = file.read_line()? {
+        match http::HttpRequest::with_options(&line, timestamp_options) {
+            Ok(request) => {
+                if let Some(device) = attribution.device(&request) {
+                    tx.insert_request(&device, &request)?;
+                    entries += 1;
                 }
-            }));
-            (k, remove_dups.collect())
-        }).collect();
-        IpToMacLookup(finalized)
-    }
-}
-
-impl IpToMacLookup {
-    fn get_mac(&self, date: NaiveDateTime, ip_addr: &str) -> Option<&str> {
-        let v: &[(NaiveDateTime, String)] = self.0.get(ip_addr)?;
-        v.iter().take_while(|&&(ack_date, _) : &&(NaiveDateTime, String)| -> bool {ack_date < date}).map(|(_, mac)| mac.as_str()).last()
-    }
-}
-
-fn read_dhcp_logs<P: AsRef<Path>>(dir: P) -> Result<(IpToMacLookup, HashMap<String, String>), Box<Error>> {
-    let mut ip_to_mac = IpToMacBuilder::new();
-    let mut mac_to_friendly_name = HashMap::new();
-    for dir_entry in fs::read_dir(dir)? {
-        let filename = dir_entry?.path();
-        let filereader = BufReader::new(File::open(&filename)?);
-        for line in filereader.split(b'\n') {
-            let line = line?;
-            match dhcp::LogEntry::new(&line) {
-                Ok(dhcp::LogEntry{ datetime, msg: dhcp::DhcpMsg::Ack{ip_addr, mac_addr, friendly_name} }) => {
-                    if let Some(friendly_name) = friendly_name {
-                        println!("friendly_name: {}", friendly_name);
-                        use std::collections::hash_map::Entry::*;
-                        match mac_to_friendly_name.entry(mac_addr.clone()) {
-                            Occupied(occupied) => {
-                                if *occupied.get() != friendly_name {
-                                    eprintln!("mac {} has multiple friendly names: ({}, {})", &mac_addr, occupied.get(), friendly_name);
-                                }
-                            },
-                            Vacant(vacant) => {
-                                vacant.insert(friendly_name);
-                            },
-                        }
-                    }
-                    ip_to_mac.add_dhcp_ack(datetime, &ip_addr, &mac_addr);
-                },
-                Ok(_) => {},
-                Err(_) => eprintln!("Failed to parse line: {}", String::from_utf8_lossy(&line)),
-            }
+            },
+            Err(e) => {
+                failures.write_all(&line)?;
+                failures.write_all(&b"\n"[..])?;
+                println!("failed processing line: {}: {}", e, String::from_utf8_lossy(&line));
+                *failure_stages.entry(e.stage).or_insert(0) += 1;
+            },
         }
     }
-    Ok((ip_to_mac.finalize(), mac_to_friendly_name))
+    file.save(&tx.tx)?;
+    Ok(entries)
 }
 
-fn run() -> Result<(), Box<Error>> {
+fn run() -> Result<(), Box<dyn Error>> {
     let opt = Opt::from_args();
     println!("{:?}", opt);
-    let (ip_to_mac, mac_to_friendly_name) = read_dhcp_logs(opt.dhcp_dir)?;
-    println!("{:?}", ip_to_mac);
-    let mut db = rusqlite::Connection::open("output.db")?;
-    let mut tx = Tx::new(&mut db)?;
-    let mut failures = File::create("failures.log")?;
+    let default_lease_time = Duration::seconds(opt.default_lease_time);
+    let mut state = match opt.ip_to_mac_state {
+        Some(ref filename) if filename.exists() => DhcpState::load(filename)?,
+        _ => DhcpState::new(default_lease_time),
+    };
+    // Bindings already in the state ended according to the lease time it
+    // was built with, so it can't be changed afterwards.
+    if state.ip_to_mac.default_lease_time() != default_lease_time {
+        return Err(format!("--default_lease_time {} doesn't match the {} seconds that --ip_to_mac_state was built with; remove the state file to rebuild it",
+            opt.default_lease_time, state.ip_to_mac.default_lease_time().num_seconds()).into());
+    }
+    let filter = FileFilter::new(&opt.include, &opt.exclude, opt.sort)?;
+    let mut filter_config = match opt.device_filter {
+        Some(ref filename) => FilterConfig::load(filename)?,
+        None => FilterConfig::default(),
+    };
+    filter_config.allow.extend(Rules { hostnames: opt.allow_host.clone(), hostname_regexes: Vec::new(), macs: opt.allow_mac.clone(), vendors: opt.allow_vendor.clone() });
+    filter_config.deny.extend(Rules { hostnames: opt.deny_host.clone(), hostname_regexes: Vec::new(), macs: opt.deny_mac.clone(), vendors: opt.deny_vendor.clone() });
+    let device_filter = DeviceFilter::new(&filter_config)?;
+    let file_config = match opt.config {
+        Some(ref filename) => Config::load(filename)?,
+        None => Config::default(),
+    };
+    let config = Config { database: opt.output.clone(), table_prefix: opt.table_prefix.clone(), mode: opt.mode, failures_log: opt.failures_log.clone() }.or(file_config);
+    let mut failures = config.open_failures_log()?;
+    let mut dhcp_files = match opt.dhcp_dir {
+        Some(ref dhcp_dir) => read_dhcp_logs(dhcp_dir, &filter, opt.timezone, &mut state, &mut failures)?,
+        None => Vec::new(),
+    };
+    for filename in &opt.dhcp_leases {
+        read_dhcp_leases(filename, &mut state)?;
+    }
+    if let Some(ref filename) = opt.ip_to_mac_state {
+        state.save(filename)?;
+    }
+    let mut ip_to_mac = state.ip_to_mac.lookup();
+    let identities = |state: &DhcpState| if opt.link_private_macs { state.links.finalize() } else { DeviceIdentities::default() };
+    let mut device_identities = identities(&state);
+    let mut table = Table::new(config.table_name("http_logs")?);
+    let mut db = rusqlite::Connection::open(config.database())?;
+    output::prepare_table(&db, config.mode(), &table.name)?;
+    let mut http_files = {
+        let mut tx = Tx::new(&mut db, &mut table)?;
+        tx.create_table()?;
+        let ledger = Ledger::load(&tx.tx, &tx.table.name)?;
+        let files = open_new_files(&ledger, &opt.http_dir, &filter, opt.timezone, &[])?;
+        tx.commit()?;
+        files
+    };
     let mut total_entries = 0;
-    for dir_entry in fs::read_dir(opt.http_dir)? {
-        let filename = dir_entry?.path();
-        let mut file_entries = 0;
-        let filereader = BufReader::new(File::open(&filename)?);
-        for line in filereader.split(b'\n') {
-            let line = line?;
-            if let Ok(log_entry) = http::LogEntry::new(&line) {
-                let mac_addr: Option<&str> = log_entry.attrs.get("srcip").and_then(|b| std::str::from_utf8(b).ok()).and_then(|ip| ip_to_mac.get_mac(log_entry.datetime, ip));
-                let friendly_name: Option<&str> = mac_addr.and_then(|mac_addr| mac_to_friendly_name.get(mac_addr).map(String::as_ref));
-                if let Some(friendly_name) = friendly_name {
-                    let friendly_name = friendly_name.to_lowercase();
-                    if FRIENDLY_NAMES.contains(friendly_name.as_str()) {
-                        tx.insert_log_entry(mac_addr, Some(&friendly_name), &log_entry)?;
-                        total_entries += 1;
-                        file_entries += 1;
-                    }
-                }
-            } else {
-                failures.write_all(&line)?;
-                failures.write_all(&b"\n"[..])?;
-                println!("failed processing line: {}", String::from_utf8_lossy(&line));
+    let mut failure_stages: BTreeMap<ParseStage, usize> = BTreeMap::new();
+    let (stop_tx, stop) = mpsc::channel();
+    if opt.follow {
+        ctrlc::set_handler(move || { let _ = stop_tx.send(()); })?;
+    }
+    let mut unsaved = false;
+    let mut last_save = time::Instant::now();
+    loop {
+        // Take in new DHCP lines first, so that traffic logged after them is
+        // attributed to the right device.
+        let dhcp_changed = match opt.dhcp_dir {
+            Some(ref dhcp_dir) if opt.follow => follow_dhcp_logs(dhcp_dir, &filter, opt.timezone, &mut dhcp_files, &mut state, &mut failures)?,
+            _ => false,
+        };
+        if dhcp_changed {
+            state.ip_to_mac.update(&mut ip_to_mac);
+            device_identities = identities(&state);
+            unsaved = true;
+        }
+        if let Some(ref filename) = opt.ip_to_mac_state {
+            if unsaved && last_save.elapsed() >= time::Duration::from_secs(opt.save_interval) {
+                state.save(filename)?;
+                unsaved = false;
+                last_save = time::Instant::now();
+            }
+        }
+        let mut tx = Tx::new(&mut db, &mut table)?;
+        let attribution = Attribution { ip_to_mac: &ip_to_mac, mac_to_friendly_name: &state.mac_to_friendly_name, identities: &device_identities, device_filter: &device_filter, keep_unknown_devices: opt.keep_unknown_devices };
+        for &mut (ref mut file, ref mut timestamp_options) in http_files.iter_mut() {
+            let rotated = opt.follow && file.rotated()?;
+            let mut file_entries = ingest_file(&mut tx, file, timestamp_options, &attribution, &mut failures, &mut failure_stages)?;
+            if rotated {
+                *file = file.reopen()?;
+                file_entries += ingest_file(&mut tx, file, timestamp_options, &attribution, &mut failures, &mut failure_stages)?;
+            }
+            if file_entries > 0 || !opt.follow {
+                println!("Added {} entries from file: {}", file_entries, file.path().to_string_lossy());
+            }
+            total_entries += file_entries;
+        }
+        if opt.follow {
+            // Only after the followed files are finished, so that a rotated
+            // log picks up from where they left off.
+            let ledger = Ledger::load(&tx.tx, &tx.table.name)?;
+            for (mut file, mut timestamp_options) in open_new_files(&ledger, &opt.http_dir, &filter, opt.timezone, &http_files)? {
+                let file_entries = ingest_file(&mut tx, &mut file, &mut timestamp_options, &attribution, &mut failures, &mut failure_stages)?;
+                println!("Added {} entries from new file: {}", file_entries, file.path().to_string_lossy());
+                total_entries += file_entries;
+                http_files.push((file, timestamp_options));
             }
         }
-        println!("Added {} entries from file: {}", file_entries, filename.to_string_lossy());
+        tx.commit()?;
+        if !opt.follow {
+            break;
+        }
+        // Report each poll's failures as they happen, rather than only once
+        // following is stopped.
+        for (stage, count) in ::std::mem::take(&mut failure_stages) {
+            println!("Failed to parse {} lines at {}", count, stage);
+        }
+        if stop.recv_timeout(time::Duration::from_secs(opt.poll_interval)).is_ok() {
+            break;
+        }
+        // Lines appended while following were logged around now.
+        for &mut (_, ref mut timestamp_options) in http_files.iter_mut() {
+            timestamp_options.refresh_reference();
+        }
+    }
+    if let Some(ref filename) = opt.ip_to_mac_state {
+        if unsaved {
+            state.save(filename)?;
+        }
     }
     println!("Added {} total entries", total_entries);
-    tx.commit()?;
+    for (stage, count) in failure_stages {
+        println!("Failed to parse {} lines at {}", count, stage);
+    }
     Ok(())
 }
 
@@ -1,21 +1,320 @@
+extern crate bzip2;
 extern crate chrono;
+extern crate chrono_tz;
 extern crate combine;
-use combine::error::{ParseError, StreamError};
-use combine::{count_min_max, token, Parser, Stream};
-use combine::parser::byte::digit;
-use combine::stream::StreamErrorFor;
+extern crate flate2;
+extern crate globset;
+extern crate phf;
+extern crate regex;
+extern crate rusqlite;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate walkdir;
+extern crate xz2;
+extern crate zstd;
+use combine::error::{self, StreamError};
+use combine::{attempt, between, choice, count_min_max, look_ahead, many1, optional, satisfy, token, Parser, Stream};
+use combine::easy;
+use combine::parser::byte::{digit, letter, space};
+use combine::stream::{PointerOffset, StreamErrorFor};
 use chrono::naive::{NaiveDate, NaiveDateTime, NaiveTime};
+use chrono::{DateTime, Datelike, Duration, FixedOffset, LocalResult, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::fmt;
+use std::str::FromStr;
+
+/// The part of a log line that a parser was working on when it failed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum ParseStage {
+    Datetime,
+    ProgramTag,
+    DhcpMsg,
+    Dhcp6Msg,
+    Lease,
+    Attribute,
+}
+
+impl fmt::Display for ParseStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ParseStage::Datetime => "datetime",
+            ParseStage::ProgramTag => "program tag",
+            ParseStage::DhcpMsg => "dhcp message",
+            ParseStage::Dhcp6Msg => "dhcpv6 message",
+            ParseStage::Lease => "lease",
+            ParseStage::Attribute => "attribute",
+        })
+    }
+}
+
+/// Describes why a log line could not be parsed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseError {
+    /// The parser stage that failed.
+    pub stage: ParseStage,
+    /// Byte offset into the line where the error occurred.
+    pub offset: usize,
+    /// Descriptions of the input that would have been accepted at `offset`.
+    pub expected: Vec<String>,
+    /// The input that was found at `offset`, if known.
+    pub found: Option<String>,
+    /// Any additional messages, such as an out of range date.
+    pub messages: Vec<String>,
+}
+
+impl ParseError {
+    fn new(line: &[u8], stage: ParseStage, err: easy::Errors<u8, &[u8], PointerOffset>) -> Self {
+        let mut parse_error = ParseError {
+            stage,
+            offset: err.position.translate_position(line),
+            expected: Vec::new(),
+            found: None,
+            messages: Vec::new(),
+        };
+        for e in err.errors {
+            match e {
+                easy::Error::Unexpected(info) => parse_error.found = Some(info_to_string(&info)),
+                easy::Error::Expected(info) => parse_error.expected.push(info_to_string(&info)),
+                easy::Error::Message(info) => parse_error.messages.push(info_to_string(&info)),
+                easy::Error::Other(err) => parse_error.messages.push(err.to_string()),
+            }
+        }
+        parse_error
+    }
+}
+
+fn info_to_string(info: &easy::Info<u8, &[u8]>) -> String {
+    match *info {
+        easy::Info::Token(b) => format!("{:?}", b as char),
+        easy::Info::Range(r) => format!("{:?}", String::from_utf8_lossy(r)),
+        easy::Info::Owned(ref s) => s.clone(),
+        easy::Info::Borrowed(s) => s.to_string(),
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to parse {} at offset {}", self.stage, self.offset)?;
+        if let Some(ref found) = self.found {
+            write!(f, ": unexpected {}", found)?;
+        }
+        if !self.expected.is_empty() {
+            write!(f, ", expected {}", self.expected.join(" or "))?;
+        }
+        for msg in &self.messages {
+            write!(f, ", {}", msg)?;
+        }
+        Ok(())
+    }
+}
+
+impl ::std::error::Error for ParseError {}
+
+/// The host and program that logged a line, taken from its syslog prefix.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SyslogHeader {
+    pub host: String,
+    pub program: String,
+    pub pid: Option<u32>,
+}
+
+/// A 48-bit hardware address, displayed in lowercase colon-separated form
+/// such as `9c:ad:97:d1:65:39`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct MacAddr(pub [u8; 6]);
+
+include!(concat!(env!("OUT_DIR"), "/oui.rs"));
+
+impl MacAddr {
+    /// The organization the IEEE assigned the address's OUI to, if it is in
+    /// the embedded registry. Locally administered addresses have none.
+    pub fn vendor(&self) -> Option<&'static str> {
+        if self.is_locally_administered() {
+            return None;
+        }
+        let b = &self.0;
+        OUI_VENDORS.get(&(u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]))).cloned()
+    }
+
+    /// Whether the address was assigned locally rather than by the device's
+    /// vendor, as the randomized private addresses of phones are.
+    pub fn is_locally_administered(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", b[0], b[1], b[2], b[3], b[4], b[5])
+    }
+}
+
+/// The error returned when a string is not a colon-separated MAC address.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseMacAddrError;
+
+impl fmt::Display for ParseMacAddrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid MAC address")
+    }
+}
+
+impl ::std::error::Error for ParseMacAddrError {}
+
+impl FromStr for MacAddr {
+    type Err = ParseMacAddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut addr = [0u8; 6];
+        let mut parts = s.split(':');
+        for b in addr.iter_mut() {
+            let part = parts.next().ok_or(ParseMacAddrError)?;
+            if part.len() != 2 || !part.bytes().all(|c| c.is_ascii_hexdigit()) {
+                return Err(ParseMacAddrError);
+            }
+            *b = u8::from_str_radix(part, 16).map_err(|_| ParseMacAddrError)?;
+        }
+        if parts.next().is_some() {
+            return Err(ParseMacAddrError);
+        }
+        Ok(MacAddr(addr))
+    }
+}
+
+impl serde::Serialize for MacAddr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for MacAddr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Settings for interpreting timestamps that leave out part of the date or
+/// their UTC offset.
+///
+/// Ambiguous local times are resolved against the previous timestamp, so a
+/// single `TimestampOptions` should be used for the lines of one log file, in
+/// order.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TimestampOptions {
+    /// BSD syslog timestamps have no year; they are assumed to fall in the
+    /// year before this time.
+    pub reference: NaiveDateTime,
+    /// The timezone of timestamps written without a UTC offset.
+    pub timezone: Tz,
+    previous: Option<DateTime<FixedOffset>>,
+}
+
+impl TimestampOptions {
+    pub fn new(timezone: Tz) -> Self {
+        TimestampOptions::with_reference(timezone, Utc::now())
+    }
+
+    /// Infers BSD syslog years relative to `reference` rather than now, such
+    /// as the modification time of an archived log.
+    pub fn with_reference<T: TimeZone>(timezone: Tz, reference: DateTime<T>) -> Self {
+        TimestampOptions {
+            reference: reference.with_timezone(&timezone).naive_local(),
+            timezone,
+            previous: None,
+        }
+    }
+
+    /// Moves `reference` up to the current time, for logs that are followed
+    /// across a change of year.
+    pub fn refresh_reference(&mut self) {
+        self.reference = Utc::now().with_timezone(&self.timezone).naive_local();
+    }
+
+    /// Attaches a UTC offset to `timestamp`.
+    ///
+    /// The hour repeated when DST ends is resolved to its first instant until
+    /// the previous timestamp shows the clock has already been set back, or
+    /// choosing the first instant would make time run backwards.
+    fn resolve(&mut self, timestamp: Timestamp) -> Result<DateTime<FixedOffset>, ParseError> {
+        let datetime = match timestamp {
+            Timestamp::Fixed(datetime) => datetime,
+            Timestamp::Local(naive) => match self.timezone.from_local_datetime(&naive) {
+                LocalResult::Single(datetime) => datetime.fixed_offset(),
+                LocalResult::Ambiguous(earliest, latest) => {
+                    let (earliest, latest) = (earliest.fixed_offset(), latest.fixed_offset());
+                    match self.previous {
+                        Some(previous) if previous.offset() == latest.offset() || previous > earliest => latest,
+                        _ => earliest,
+                    }
+                },
+                LocalResult::None => return Err(ParseError {
+                    stage: ParseStage::Datetime,
+                    offset: 0,
+                    expected: Vec::new(),
+                    found: None,
+                    messages: vec![format!("{} does not exist in {}", naive, self.timezone)],
+                }),
+            },
+        };
+        self.previous = Some(datetime);
+        Ok(datetime)
+    }
+}
+
+impl Default for TimestampOptions {
+    fn default() -> Self {
+        TimestampOptions::new(Tz::UTC)
+    }
+}
+
+/// A parsed timestamp, before a timezone has been applied to formats that
+/// lack an offset.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Timestamp {
+    Local(NaiveDateTime),
+    Fixed(DateTime<FixedOffset>),
+}
+
+/// Runs `parser` against `input`, which must be a suffix of `line`, and
+/// reports failures as a `ParseError` for `stage` with an offset relative to
+/// the start of `line`.
+fn parse_stage<'a, P>(
+    line: &'a [u8],
+    input: &'a [u8],
+    stage: ParseStage,
+    mut parser: P,
+) -> Result<(P::Output, &'a [u8]), ParseError>
+where
+    P: Parser<Input = easy::Stream<&'a [u8]>>,
+{
+    parser
+        .easy_parse(input)
+        .map_err(|err| ParseError::new(line, stage, err))
+}
 
 fn date<'a, I>() -> impl Parser<Input = I, Output = NaiveDate> + 'a
 where
     I: Stream<Item = u8, Range = &'a [u8]> + 'a,
-    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Error: error::ParseError<I::Item, I::Range, I::Position>,
+{
+    ymd(b':')
+}
+
+/// A `YYYY<sep>MM<sep>DD` date.
+fn ymd<'a, I>(sep: u8) -> impl Parser<Input = I, Output = NaiveDate> + 'a
+where
+    I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+    I::Error: error::ParseError<I::Item, I::Range, I::Position>,
 {
     (
         count_min_max::<Vec<u8>, _>(4, 4, digit()),
-        token(b':'),
+        token(sep),
         count_min_max::<Vec<u8>, _>(2, 2, digit()),
-        token(b':'),
+        token(sep),
         count_min_max::<Vec<u8>, _>(2, 2, digit()),
     ).and_then(|(y, _, m, _, d)| {
         let y: i32 = String::from_utf8(y).map_err(StreamErrorFor::<I>::other)?.parse().map_err(StreamErrorFor::<I>::other)?;
@@ -27,10 +326,11 @@ where
     })
 }
 
+/// An `HH:MM:SS` time with optional fractional seconds, down to nanoseconds.
 fn time<'a, I>() -> impl Parser<Input = I, Output = NaiveTime> + 'a
 where
     I: Stream<Item = u8, Range = &'a [u8]> + 'a,
-    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Error: error::ParseError<I::Item, I::Range, I::Position>,
 {
     (
         count_min_max::<Vec<u8>, _>(2, 2, digit()),
@@ -38,11 +338,19 @@ where
         count_min_max::<Vec<u8>, _>(2, 2, digit()),
         token(b':'),
         count_min_max::<Vec<u8>, _>(2, 2, digit()),
-    ).and_then(|(h, _, m, _, s)| {
+        optional(attempt(token(b'.').with(count_min_max::<Vec<u8>, _>(1, 9, digit())))),
+    ).and_then(|(h, _, m, _, s, frac): (_, _, _, _, _, Option<Vec<u8>>)| {
         let h: u32 = String::from_utf8(h).map_err(StreamErrorFor::<I>::other)?.parse().map_err(StreamErrorFor::<I>::other)?;
         let m: u32 = String::from_utf8(m).map_err(StreamErrorFor::<I>::other)?.parse().map_err(StreamErrorFor::<I>::other)?;
         let s: u32 = String::from_utf8(s).map_err(StreamErrorFor::<I>::other)?.parse().map_err(StreamErrorFor::<I>::other)?;
-        NaiveTime::from_hms_opt(h, m, s).ok_or(StreamErrorFor::<I>::unexpected_static_message(
+        let nano: u32 = match frac {
+            Some(mut frac) => {
+                frac.resize(9, b'0');
+                String::from_utf8(frac).map_err(StreamErrorFor::<I>::other)?.parse().map_err(StreamErrorFor::<I>::other)?
+            },
+            None => 0,
+        };
+        NaiveTime::from_hms_nano_opt(h, m, s, nano).ok_or(StreamErrorFor::<I>::unexpected_static_message(
             "failed to build NaiveTime",
         ))
     })
@@ -52,21 +360,158 @@ fn datetime<'a, I>() -> impl Parser<Input = I, Output = NaiveDateTime> + 'a
 where
     I: Stream<Item = u8, Range = &'a [u8]> + 'a,
     // Necessary due to rust-lang/rust#24159
-    I::Error: ParseError<I::Item, I::Range, I::Position>,
+    I::Error: error::ParseError<I::Item, I::Range, I::Position>,
 {
     (date(), token(b'-'), time()).map(|(date, _, time)| NaiveDateTime::new(date, time))
 }
 
+/// An RFC 5424 / RFC 3339 timestamp such as `2016-04-03T23:59:59.123+02:00`.
+fn rfc5424_datetime<'a, I>() -> impl Parser<Input = I, Output = DateTime<FixedOffset>> + 'a
+where
+    I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+    // Necessary due to rust-lang/rust#24159
+    I::Error: error::ParseError<I::Item, I::Range, I::Position>,
+{
+    (
+        ymd(b'-'),
+        token(b'T'),
+        time(),
+        utc_offset(),
+    ).and_then(|(date, _, time, offset)| {
+        offset.from_local_datetime(&NaiveDateTime::new(date, time)).single().ok_or(
+            StreamErrorFor::<I>::unexpected_static_message("failed to build DateTime"),
+        )
+    })
+}
+
+/// A `Z` or `+HH:MM` / `-HH:MM` offset from UTC.
+fn utc_offset<'a, I>() -> impl Parser<Input = I, Output = FixedOffset> + 'a
+where
+    I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+    // Necessary due to rust-lang/rust#24159
+    I::Error: error::ParseError<I::Item, I::Range, I::Position>,
+{
+    let numeric = (
+        token(b'+').or(token(b'-')),
+        count_min_max::<Vec<u8>, _>(2, 2, digit()),
+        token(b':'),
+        count_min_max::<Vec<u8>, _>(2, 2, digit()),
+    ).and_then(|(sign, h, _, m)| {
+        let h: i32 = String::from_utf8(h).map_err(StreamErrorFor::<I>::other)?.parse().map_err(StreamErrorFor::<I>::other)?;
+        let m: i32 = String::from_utf8(m).map_err(StreamErrorFor::<I>::other)?.parse().map_err(StreamErrorFor::<I>::other)?;
+        let secs = if sign == b'-' { -(h * 3600 + m * 60) } else { h * 3600 + m * 60 };
+        FixedOffset::east_opt(secs).ok_or(StreamErrorFor::<I>::unexpected_static_message(
+            "failed to build FixedOffset",
+        ))
+    });
+    token(b'Z').map(|_| FixedOffset::east_opt(0).unwrap()).or(numeric)
+}
+
+/// A BSD (RFC 3164) timestamp such as `Apr  3 23:59:59`.
+///
+/// These carry no year, so the year is chosen to place the timestamp in the
+/// twelve months leading up to `reference`, allowing a day of clock skew.
+fn bsd_datetime<'a, I>(reference: NaiveDateTime) -> impl Parser<Input = I, Output = NaiveDateTime> + 'a
+where
+    I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+    // Necessary due to rust-lang/rust#24159
+    I::Error: error::ParseError<I::Item, I::Range, I::Position>,
+{
+    (
+        count_min_max::<Vec<u8>, _>(3, 3, letter()),
+        many1::<Vec<u8>, _>(token(b' ')),
+        count_min_max::<Vec<u8>, _>(1, 2, digit()),
+        token(b' '),
+        time(),
+    ).and_then(move |(mon, _, d, _, time)| {
+        let m: u32 = match &mon[..] {
+            b"Jan" => 1, b"Feb" => 2, b"Mar" => 3, b"Apr" => 4, b"May" => 5, b"Jun" => 6,
+            b"Jul" => 7, b"Aug" => 8, b"Sep" => 9, b"Oct" => 10, b"Nov" => 11, b"Dec" => 12,
+            _ => return Err(StreamErrorFor::<I>::unexpected_static_message("unknown month")),
+        };
+        let d: u32 = String::from_utf8(d).map_err(StreamErrorFor::<I>::other)?.parse().map_err(StreamErrorFor::<I>::other)?;
+        let latest = reference + Duration::days(1);
+        let at_year = |y| NaiveDate::from_ymd_opt(y, m, d).map(|date| NaiveDateTime::new(date, time));
+        match at_year(reference.year()) {
+            Some(datetime) if datetime <= latest => Some(datetime),
+            _ => at_year(reference.year() - 1),
+        }.ok_or(StreamErrorFor::<I>::unexpected_static_message(
+            "failed to build NaiveDate",
+        ))
+    })
+}
+
+/// Any of the supported syslog timestamp formats.
+fn timestamp<'a, I>(reference: NaiveDateTime) -> impl Parser<Input = I, Output = Timestamp> + 'a
+where
+    I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+    // Necessary due to rust-lang/rust#24159
+    I::Error: error::ParseError<I::Item, I::Range, I::Position>,
+{
+    // The numeric formats start with a four digit year; commit to one as soon
+    // as the separator is known so errors point at the real problem.
+    let year_then = |sep| attempt(look_ahead((count_min_max::<Vec<u8>, _>(4, 4, digit()), token(sep))));
+    choice((
+        year_then(b':').with(datetime()).map(Timestamp::Local),
+        year_then(b'-').with(choice((
+            attempt(look_ahead((ymd(b'-'), token(b' ')))).with(kea_datetime()).map(Timestamp::Local),
+            rfc5424_datetime().map(Timestamp::Fixed),
+        ))),
+        bsd_datetime(reference).map(Timestamp::Local),
+    ))
+}
+
+/// A `2019-05-01 12:00:00.123` local time, as Kea writes to its own log
+/// files.
+fn kea_datetime<'a, I>() -> impl Parser<Input = I, Output = NaiveDateTime> + 'a
+where
+    I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+    // Necessary due to rust-lang/rust#24159
+    I::Error: error::ParseError<I::Item, I::Range, I::Position>,
+{
+    (ymd(b'-'), token(b' '), time()).map(|(date, _, time)| NaiveDateTime::new(date, time))
+}
+
+/// Parses the syslog host and program tag that precede the message body, e.g.
+/// ` publicwifi httpproxy[18500]: `.
+fn syslog_header<'a, I>() -> impl Parser<Input = I, Output = SyslogHeader> + 'a
+where
+    I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+    // Necessary due to rust-lang/rust#24159
+    I::Error: error::ParseError<I::Item, I::Range, I::Position>,
+{
+    (
+        space(),
+        many1::<Vec<u8>, _>(satisfy(|c| c != b' ' && c != b':')),
+        space(),
+        many1::<Vec<u8>, _>(satisfy(|c| c != b' ' && c != b':' && c != b'[')),
+        optional(between(token(b'['), token(b']'), many1::<Vec<u8>, _>(digit()))),
+        token(b':'),
+        space(),
+    ).and_then(|(_, host, _, program, pid, _, _): (_, _, _, _, Option<Vec<u8>>, _, _)| -> Result<_, StreamErrorFor<I>> {
+        let host = String::from_utf8(host).map_err(StreamErrorFor::<I>::other)?;
+        let program = String::from_utf8(program).map_err(StreamErrorFor::<I>::other)?;
+        let pid = match pid {
+            Some(pid) => Some(String::from_utf8(pid).map_err(StreamErrorFor::<I>::other)?.parse().map_err(StreamErrorFor::<I>::other)?),
+            None => None,
+        };
+        Ok(SyslogHeader { host, program, pid })
+    })
+}
+
 #[cfg(test)]
 mod tests {
+    use {MacAddr, ParseStage, SyslogHeader, Timestamp, TimestampOptions};
     use combine::Parser;
     use chrono::naive::{NaiveDate, NaiveDateTime, NaiveTime};
+    use chrono::{Duration, FixedOffset, TimeZone, Utc};
+    use chrono_tz::America::New_York;
 
     #[test]
     fn date() {
         assert_eq!(
             ::date().parse(&b"2016:04:03"[..]),
-            Ok((NaiveDate::from_ymd(2016, 4, 3), &b""[..]))
+            Ok((NaiveDate::from_ymd_opt(2016, 4, 3).unwrap(), &b""[..]))
         );
     }
 
@@ -74,317 +519,1626 @@ mod tests {
     fn time() {
         assert_eq!(
             super::time().parse(&b"23:59:59"[..]),
-            Ok((NaiveTime::from_hms(23, 59, 59), &b""[..]))
+            Ok((NaiveTime::from_hms_opt(23, 59, 59).unwrap(), &b""[..]))
+        );
+        assert_eq!(
+            super::time().parse(&b"23:59:59.123"[..]),
+            Ok((NaiveTime::from_hms_milli_opt(23, 59, 59, 123).unwrap(), &b""[..]))
+        );
+        assert_eq!(
+            super::time().parse(&b"23:59:59.123456"[..]),
+            Ok((NaiveTime::from_hms_micro_opt(23, 59, 59, 123_456).unwrap(), &b""[..]))
+        );
+        assert_eq!(
+            super::time().parse(&b"23:59:59.123456789 "[..]),
+            Ok((NaiveTime::from_hms_nano_opt(23, 59, 59, 123_456_789).unwrap(), &b" "[..]))
+        );
+        assert_eq!(
+            super::time().parse(&b"23:59:59. "[..]),
+            Ok((NaiveTime::from_hms_opt(23, 59, 59).unwrap(), &b". "[..]))
         );
     }
 
     #[test]
     fn datetime() {
         let want = NaiveDateTime::new(
-            NaiveDate::from_ymd(2016, 4, 3),
-            NaiveTime::from_hms(23, 59, 59),
+            NaiveDate::from_ymd_opt(2016, 4, 3).unwrap(),
+            NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
         );
         assert_eq!(
             super::datetime().parse(&b"2016:04:03-23:59:59"[..]),
             Ok((want, &b""[..]))
         );
     }
+
+    #[test]
+    fn rfc5424_datetime() {
+        let naive = NaiveDateTime::new(
+            NaiveDate::from_ymd_opt(2016, 4, 3).unwrap(),
+            NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
+        );
+        let plus_two = FixedOffset::east_opt(2 * 3600).unwrap();
+        let naive_ms = NaiveDateTime::new(
+            NaiveDate::from_ymd_opt(2016, 4, 3).unwrap(),
+            NaiveTime::from_hms_milli_opt(23, 59, 59, 123).unwrap(),
+        );
+        assert_eq!(
+            super::rfc5424_datetime().parse(&b"2016-04-03T23:59:59.123+02:00"[..]),
+            Ok((plus_two.from_local_datetime(&naive_ms).unwrap(), &b""[..]))
+        );
+        let utc = FixedOffset::east_opt(0).unwrap();
+        assert_eq!(
+            super::rfc5424_datetime().parse(&b"2016-04-03T23:59:59Z"[..]),
+            Ok((utc.from_local_datetime(&naive).unwrap(), &b""[..]))
+        );
+    }
+
+    #[test]
+    fn bsd_datetime() {
+        let reference = NaiveDateTime::new(
+            NaiveDate::from_ymd_opt(2017, 1, 2).unwrap(),
+            NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+        );
+        assert_eq!(
+            super::bsd_datetime(reference).parse(&b"Jan  2 23:59:59"[..]),
+            Ok((NaiveDateTime::new(NaiveDate::from_ymd_opt(2017, 1, 2).unwrap(), NaiveTime::from_hms_opt(23, 59, 59).unwrap()), &b""[..]))
+        );
+        assert_eq!(
+            super::bsd_datetime(reference).parse(&b"Dec 31 23:59:59"[..]),
+            Ok((NaiveDateTime::new(NaiveDate::from_ymd_opt(2016, 12, 31).unwrap(), NaiveTime::from_hms_opt(23, 59, 59).unwrap()), &b""[..]))
+        );
+        assert!(super::bsd_datetime(reference).parse(&b"Foo 31 23:59:59"[..]).is_err());
+    }
+
+    #[test]
+    fn timestamp() {
+        let reference = NaiveDateTime::new(
+            NaiveDate::from_ymd_opt(2016, 6, 1).unwrap(),
+            NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+        );
+        let naive = NaiveDateTime::new(
+            NaiveDate::from_ymd_opt(2016, 4, 3).unwrap(),
+            NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
+        );
+        for input in &[&b"2016:04:03-23:59:59"[..], &b"Apr  3 23:59:59"[..]] {
+            assert_eq!(super::timestamp(reference).parse(*input), Ok((Timestamp::Local(naive), &b""[..])));
+        }
+        assert_eq!(
+            super::timestamp(reference).parse(&b"2016-04-03 23:59:59.250"[..]),
+            Ok((Timestamp::Local(naive + Duration::milliseconds(250)), &b""[..]))
+        );
+        let plus_two = FixedOffset::east_opt(2 * 3600).unwrap();
+        assert_eq!(
+            super::timestamp(reference).parse(&b"2016-04-03T23:59:59+02:00"[..]),
+            Ok((Timestamp::Fixed(plus_two.from_local_datetime(&naive).unwrap()), &b""[..]))
+        );
+    }
+
+    #[test]
+    fn resolve() {
+        let mut options = TimestampOptions::new(New_York);
+        let local = |h, m| Timestamp::Local(NaiveDateTime::new(
+            NaiveDate::from_ymd_opt(2016, 11, 6).unwrap(),
+            NaiveTime::from_hms_opt(h, m, 0).unwrap(),
+        ));
+        let utc = |h, m| Utc.with_ymd_and_hms(2016, 11, 6, h, m, 0).unwrap();
+        // 01:00-01:59 happens twice as EDT (UTC-4) falls back to EST (UTC-5).
+        assert_eq!(options.resolve(local(0, 59)).unwrap(), utc(4, 59));
+        assert_eq!(options.resolve(local(1, 30)).unwrap(), utc(5, 30));
+        assert_eq!(options.resolve(local(1, 59)).unwrap(), utc(5, 59));
+        assert_eq!(options.resolve(local(1, 0)).unwrap(), utc(6, 0));
+        assert_eq!(options.resolve(local(1, 30)).unwrap(), utc(6, 30));
+        assert_eq!(options.resolve(local(2, 0)).unwrap(), utc(7, 0));
+
+        // 02:30 is skipped when EST springs forward to EDT.
+        let skipped = Timestamp::Local(NaiveDateTime::new(
+            NaiveDate::from_ymd_opt(2016, 3, 13).unwrap(),
+            NaiveTime::from_hms_opt(2, 30, 0).unwrap(),
+        ));
+        assert_eq!(options.resolve(skipped).unwrap_err().stage, ParseStage::Datetime);
+    }
+
+    #[test]
+    fn with_reference() {
+        // An archived log from a previous year.
+        let mut options = TimestampOptions::with_reference(::chrono_tz::UTC, Utc.with_ymd_and_hms(2015, 6, 1, 0, 0, 0).unwrap());
+        let entry = ::http::LogEntry::with_options(&br#"Mar  3 10:00:00 publicwifi httpproxy[18500]: foo="bar""#[..], &mut options).unwrap();
+        assert_eq!(entry.datetime, Utc.with_ymd_and_hms(2015, 3, 3, 10, 0, 0).unwrap().fixed_offset());
+    }
+
+    #[test]
+    fn mac_addr() {
+        let mac: MacAddr = "A4:DB:30:66:4F:90".parse().unwrap();
+        assert_eq!(mac, MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]));
+        assert_eq!(mac.to_string(), "a4:db:30:66:4f:90");
+        assert!("a4:db:30:66:4f".parse::<MacAddr>().is_err());
+        assert!("a4:db:30:66:4f:90:00".parse::<MacAddr>().is_err());
+        assert!("a4:db:30:66:4f:9".parse::<MacAddr>().is_err());
+        assert!("a4:db:30:66:4f:+9".parse::<MacAddr>().is_err());
+    }
+
+    #[test]
+    fn vendor() {
+        let pi: MacAddr = "b8:27:eb:12:34:56".parse().unwrap();
+        assert_eq!(pi.vendor(), Some("Raspberry Pi Foundation"));
+        assert!(!pi.is_locally_administered());
+        // A randomized private address.
+        let private: MacAddr = "ba:27:eb:12:34:56".parse().unwrap();
+        assert!(private.is_locally_administered());
+        assert_eq!(private.vendor(), None);
+    }
+
+    #[test]
+    fn syslog_header() {
+        assert_eq!(
+            super::syslog_header().parse(&b" publicwifi httpproxy[18500]: "[..]),
+            Ok((SyslogHeader { host: "publicwifi".to_string(), program: "httpproxy".to_string(), pid: Some(18500) }, &b""[..]))
+        );
+        assert_eq!(
+            super::syslog_header().parse(&b" PublicWiFi dhcpd: DHCPACK"[..]),
+            Ok((SyslogHeader { host: "PublicWiFi".to_string(), program: "dhcpd".to_string(), pid: None }, &b"DHCPACK"[..]))
+        );
+    }
 }
 
 pub mod dhcp {
-    use chrono::NaiveDateTime;
+    use chrono::{DateTime, FixedOffset};
+    use {MacAddr, ParseStage, SyslogHeader, TimestampOptions};
     use combine::{
-        optional, many1, satisfy, token, Parser, Stream, try, choice, count_min_max,
+        optional, look_ahead, many, many1, satisfy, skip_many, skip_many1, token, Parser, Stream, attempt, choice, count_min_max,
         error::{ParseError, StreamError},
         stream::StreamErrorFor,
-        parser::byte::{digit, space, bytes}};
+        parser::byte::{digit, hex_digit, space, bytes}};
+    use std::net::Ipv4Addr;
 
     #[derive(Debug, PartialEq, Clone)]
     pub struct LogEntry {
-        pub datetime: NaiveDateTime,
+        pub datetime: DateTime<FixedOffset>,
+        pub header: SyslogHeader,
         pub msg: DhcpMsg,
     }
 
+    /// A DHCP message logged by ISC dhcpd, dnsmasq or Kea.
+    ///
+    /// `via` is the interface the message arrived on, or the address of the
+    /// relay agent that forwarded it. `reason` holds the explanation the
+    /// server appends to the message, such as `no free leases` or
+    /// `wrong network.` `lease_time` is the length of the lease in seconds,
+    /// which only Kea logs.
     #[derive(Debug, PartialEq, Clone)]
     pub enum DhcpMsg {
-        Inform,
-        Offer,
-        Ack{ ip_addr: String, mac_addr: String, friendly_name: Option<String> },
-        Nak,
-        Request,
-        Discover,
+        Inform{ ip_addr: Ipv4Addr, via: Option<String>, reason: Option<String> },
+        Offer{ ip_addr: Ipv4Addr, mac_addr: MacAddr, friendly_name: Option<String>, via: Option<String>, reason: Option<String> },
+        /// `client_id` is the DHCP client identifier, which only Kea logs.
+        Ack{ ip_addr: Ipv4Addr, mac_addr: MacAddr, friendly_name: Option<String>, client_id: Option<Vec<u8>>, lease_time: Option<u32>, via: Option<String>, reason: Option<String> },
+        Nak{ ip_addr: Ipv4Addr, mac_addr: MacAddr, via: Option<String>, reason: Option<String> },
+        Request{ ip_addr: Ipv4Addr, server_ip: Option<Ipv4Addr>, mac_addr: MacAddr, friendly_name: Option<String>, via: Option<String>, reason: Option<String> },
+        Discover{ mac_addr: MacAddr, friendly_name: Option<String>, via: Option<String>, reason: Option<String> },
+        Release{ ip_addr: Ipv4Addr, mac_addr: MacAddr, via: Option<String>, reason: Option<String> },
+        Decline{ ip_addr: Ipv4Addr, mac_addr: MacAddr, via: Option<String>, reason: Option<String> },
+        /// A lease the server reclaimed after it expired.
+        Expire{ ip_addr: Ipv4Addr, mac_addr: MacAddr, via: Option<String>, reason: Option<String> },
+    }
+
+    impl DhcpMsg {
+        /// The message type as it appears in the log, e.g. `DHCPACK`, or
+        /// `EXPIRE` for a reclaimed lease.
+        pub fn msg_type(&self) -> &'static str {
+            match *self {
+                DhcpMsg::Inform{..} => "DHCPINFORM",
+                DhcpMsg::Offer{..} => "DHCPOFFER",
+                DhcpMsg::Ack{..} => "DHCPACK",
+                DhcpMsg::Nak{..} => "DHCPNAK",
+                DhcpMsg::Request{..} => "DHCPREQUEST",
+                DhcpMsg::Discover{..} => "DHCPDISCOVER",
+                DhcpMsg::Release{..} => "DHCPRELEASE",
+                DhcpMsg::Decline{..} => "DHCPDECLINE",
+                DhcpMsg::Expire{..} => "EXPIRE",
+            }
+        }
     }
 
     impl LogEntry {
-        pub fn new(s: &[u8]) -> Result<Self, Box<::std::error::Error>> {
-            log_entry().easy_parse(s).map(|x| x.0).map_err(|_| "error".into())
+        pub fn new(s: &[u8]) -> Result<Self, ::ParseError> {
+            Self::with_options(s, &mut TimestampOptions::default())
+        }
+
+        pub fn with_options(s: &[u8], options: &mut TimestampOptions) -> Result<Self, ::ParseError> {
+            let (datetime, rest) = ::parse_stage(s, s, ParseStage::Datetime, ::timestamp(options.reference))?;
+            let datetime = options.resolve(datetime)?;
+            let (header, rest) = ::parse_stage(s, rest, ParseStage::ProgramTag, attempt(kea_header()).or(::syslog_header()))?;
+            let (msg, _) = ::parse_stage(s, rest, ParseStage::DhcpMsg, dhcp_msg())?;
+            Ok(LogEntry { datetime, header, msg })
         }
     }
 
-    fn log_entry<'a, I>() -> impl Parser<Input = I, Output = LogEntry> + 'a
+    fn dhcp_msg<'a, I>() -> impl Parser<Input = I, Output = DhcpMsg> + 'a
     where
         I: Stream<Item = u8, Range = &'a [u8]> + 'a,
         // Necessary due to rust-lang/rust#24159
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
-        (
-            ::datetime(),
-            many1::<Vec<u8>, _>(satisfy(|c| c != b':')),
-            token(b':'),
-            space(),
-            dhcp_msg(),
-        ).map(|(datetime, _, _, _, msg)| LogEntry { datetime, msg })
+        // dnsmasq may prefix the message with a transaction id, and follows the
+        // type with `(<iface>)` where dhcpd has a space.
+        let msg = (
+            optional(attempt(many1::<Vec<u8>, _>(digit()).skip(space()))),
+            attempt(bytes(&b"DHCP"[..])),
+            choice((
+                attempt(bytes(&b"INFORM"[..])).with(dhcp_inform().or(dnsmasq(|f| Some(DhcpMsg::Inform{
+                    ip_addr: f.ip_addr?, via: f.via, reason: f.rest,
+                })))),
+                attempt(bytes(&b"OFFER"[..])).with(dhcp_offer().or(dnsmasq(|f| Some(DhcpMsg::Offer{
+                    ip_addr: f.ip_addr?, mac_addr: f.mac_addr?, friendly_name: None, via: f.via, reason: f.rest,
+                })))),
+                attempt(bytes(&b"ACK"[..])).with(dhcp_ack().or(dnsmasq(|f| Some(DhcpMsg::Ack{
+                    ip_addr: f.ip_addr?, mac_addr: f.mac_addr?, friendly_name: f.rest, client_id: None, lease_time: None, via: f.via, reason: None,
+                })))),
+                attempt(bytes(&b"NAK"[..])).with(dhcp_nak().or(dnsmasq(|f| Some(DhcpMsg::Nak{
+                    ip_addr: f.ip_addr?, mac_addr: f.mac_addr?, via: f.via, reason: f.rest,
+                })))),
+                attempt(bytes(&b"REQUEST"[..])).with(dhcp_request().or(dnsmasq(|f| Some(DhcpMsg::Request{
+                    ip_addr: f.ip_addr?, server_ip: None, mac_addr: f.mac_addr?, friendly_name: None, via: f.via, reason: f.rest,
+                })))),
+                attempt(bytes(&b"DISCOVER"[..])).with(dhcp_discover().or(dnsmasq(|f| Some(DhcpMsg::Discover{
+                    mac_addr: f.mac_addr?, friendly_name: None, via: f.via, reason: f.rest,
+                })))),
+                attempt(bytes(&b"RELEASE"[..])).with(dhcp_release().map(|(ip_addr, mac_addr, via, reason)| DhcpMsg::Release{
+                    ip_addr, mac_addr, via, reason,
+                }).or(dnsmasq(|f| Some(DhcpMsg::Release{
+                    ip_addr: f.ip_addr?, mac_addr: f.mac_addr?, via: f.via, reason: f.rest,
+                })))),
+                attempt(bytes(&b"DECLINE"[..])).with(dhcp_release().map(|(ip_addr, mac_addr, via, reason)| DhcpMsg::Decline{
+                    ip_addr, mac_addr, via, reason,
+                }).or(dnsmasq(|f| Some(DhcpMsg::Decline{
+                    ip_addr: f.ip_addr?, mac_addr: f.mac_addr?, via: f.via, reason: f.rest,
+                })))),
+            ))
+        ).map(|(_, _, msg)| msg);
+        msg.or(kea_msg())
     }
 
-    fn dhcp_msg<'a, I>() -> impl Parser<Input = I, Output = DhcpMsg> + 'a
+    /// Kea's own log files have no syslog header; the timestamp is followed
+    /// by `<severity> [<logger>/<pid>.<tid>]`, which `kea_msg` goes on to
+    /// parse. The program is the logger's root, e.g. `kea-dhcp4` for
+    /// `kea-dhcp4.leases`, and there is no host.
+    fn kea_header<'a, I>() -> impl Parser<Input = I, Output = SyslogHeader> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        space().with(look_ahead((
+            skip_many1(satisfy(|c: u8| c.is_ascii_uppercase())),
+            skip_many1(space()),
+            token(b'['),
+            many1::<Vec<u8>, _>(satisfy(|c| c != b'.' && c != b'/' && c != b']')),
+            skip_many(satisfy(|c| c != b'/' && c != b']')),
+            token(b'/'),
+            many1::<Vec<u8>, _>(digit()),
+        ))).and_then(|(_, _, _, program, _, _, pid)| -> Result<_, StreamErrorFor<I>> {
+            let program = String::from_utf8(program).map_err(StreamErrorFor::<I>::other)?;
+            let pid = String::from_utf8(pid).map_err(StreamErrorFor::<I>::other)?.parse().map_err(StreamErrorFor::<I>::other)?;
+            Ok(SyslogHeader { host: String::new(), program, pid: Some(pid) })
+        })
+    }
+
+    /// `<severity>  [<logger>/<pid>.<tid>] <message id> <text>` as logged by
+    /// Kea, after a syslog header or in its own log files.
+    ///
+    /// Only the messages that allocate, renew, release or reclaim a lease are
+    /// understood. Kea logs renewals as allocations.
+    fn kea_msg<'a, I>() -> impl Parser<Input = I, Output = DhcpMsg> + 'a
     where
         I: Stream<Item = u8, Range = &'a [u8]> + 'a,
         // Necessary due to rust-lang/rust#24159
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
         (
-            bytes(&b"DHCP"[..]),
+            skip_many1(satisfy(|c: u8| c.is_ascii_uppercase())),
+            skip_many1(space()),
+            token(b'['),
+            skip_many1(satisfy(|c| c != b']')),
+            bytes(&b"] "[..]),
             choice((
-                try(bytes(&b"INFORM"[..]).map(|_| DhcpMsg::Inform)),
-                try(bytes(&b"OFFER"[..]).map(|_| DhcpMsg::Offer)),
-                try(bytes(&b"ACK"[..]).with(dhcp_ack())),
-                try(bytes(&b"NAK"[..]).map(|_| DhcpMsg::Nak)),
-                try(bytes(&b"REQUEST"[..]).map(|_| DhcpMsg::Request)),
-                try(bytes(&b"DISCOVER"[..]).map(|_| DhcpMsg::Discover)),
-            ))
-        ).map(|(_, msg)| msg)
+                attempt(bytes(&b"DHCP4_LEASE_ALLOC "[..])).with(kea_lease(&b"lease "[..], |ip_addr, mac_addr, client_id, lease_time| DhcpMsg::Ack{
+                    ip_addr, mac_addr, friendly_name: None, client_id, lease_time, via: None, reason: None,
+                })),
+                attempt(bytes(&b"DHCP4_LEASE_REUSE "[..])).with(kea_lease(&b"lease "[..], |ip_addr, mac_addr, client_id, lease_time| DhcpMsg::Ack{
+                    ip_addr, mac_addr, friendly_name: None, client_id, lease_time, via: None, reason: None,
+                })),
+                attempt(bytes(&b"DHCP4_LEASE_ADVERT "[..])).with(kea_lease(&b"lease "[..], |ip_addr, mac_addr, _, _| DhcpMsg::Offer{
+                    ip_addr, mac_addr, friendly_name: None, via: None, reason: None,
+                })),
+                attempt(bytes(&b"DHCP4_RELEASE "[..])).with(kea_lease(&b"address "[..], |ip_addr, mac_addr, _, _| DhcpMsg::Release{
+                    ip_addr, mac_addr, via: None, reason: None,
+                })),
+                attempt(bytes(&b"ALLOC_ENGINE_V4_LEASE_RECLAIM "[..])).with(kea_lease(&b"reclaiming expired lease for address "[..], |ip_addr, mac_addr, _, _| DhcpMsg::Expire{
+                    ip_addr, mac_addr, via: None, reason: None,
+                })),
+            )),
+        ).map(|(_, _, _, _, _, msg)| msg)
     }
 
-    fn dhcp_ack<'a, I>() -> impl Parser<Input = I, Output = DhcpMsg> + 'a
+    /// `[hwtype=<n> <mac>], cid=[<id>], tid=0x<xid>: <prefix><ip> ...`, the
+    /// client label and address Kea logs for lease events. The transaction id
+    /// is absent when no packet is involved, such as when reclaiming a lease.
+    /// The text after the address may end with `for <n> seconds`, the lease
+    /// time.
+    fn kea_lease<'a, I>(prefix: &'static [u8], make: fn(Ipv4Addr, MacAddr, Option<Vec<u8>>, Option<u32>) -> DhcpMsg) -> impl Parser<Input = I, Output = DhcpMsg> + 'a
     where
         I: Stream<Item = u8, Range = &'a [u8]> + 'a,
         // Necessary due to rust-lang/rust#24159
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
         (
+            bytes(&b"[hwtype="[..]),
+            skip_many1(digit()),
             space(),
-            bytes(&b"on "[..]).or(bytes(&b"to "[..])),
-            ip_addr(),
-            space(),
-            bytes(&b"to "[..]).or(bytes(&b"("[..])),
             mac_addr(),
-            bytes(&b") "[..]).or(bytes(&b" "[..])),
-            optional((
-                try(bytes(&b"("[..])),
-                many1::<Vec<u8>, _>(satisfy(|c| c != b')')),
-                bytes(&b")"[..]))),
-        ).map(|(_, _, ip_addr, _, _, mac_addr, _, opt_name)| {
-            let opt_name: Option<(_, Vec<u8>, _)> = opt_name;
-            let friendly_name = opt_name.and_then(|(_, friendly_name, _)| String::from_utf8(friendly_name).ok());
-            DhcpMsg::Ack{ip_addr, mac_addr, friendly_name}
-        })
+            bytes(&b"], cid=["[..]),
+            many::<Vec<u8>, _>(satisfy(|c| c != b']')).skip(token(b']')).map(|cid| client_id(&cid)),
+            optional(attempt(bytes(&b", tid=0x"[..])).with(skip_many1(hex_digit()))),
+            bytes(&b": "[..]).with(bytes(prefix)),
+            ip_addr(),
+            optional(attempt((
+                skip_many1(satisfy(|c: u8| c.is_ascii_lowercase() || c == b' ')),
+                many1::<String, _>(digit().map(char::from)).and_then(|secs| secs.parse::<u32>().map_err(StreamErrorFor::<I>::other)),
+                bytes(&b" seconds"[..]),
+            ).map(|(_, secs, _)| secs))),
+            skip_many(satisfy(|c| c != b'\n')),
+        ).map(move |(_, _, _, mac_addr, _, client_id, _, _, ip_addr, lease_time, _)| make(ip_addr, mac_addr, client_id, lease_time))
+    }
+
+    /// Colon separated hex bytes as Kea logs a client identifier, or `None`
+    /// for anything else such as `no info`.
+    fn client_id(cid: &[u8]) -> Option<Vec<u8>> {
+        let cid = ::std::str::from_utf8(cid).ok()?;
+        cid.split(':')
+            .map(|byte| if byte.len() == 2 { u8::from_str_radix(byte, 16).ok() } else { None })
+            .collect()
+    }
+
+    /// The fields dnsmasq logs after the message type.
+    struct DnsmasqFields {
+        via: Option<String>,
+        ip_addr: Option<Ipv4Addr>,
+        mac_addr: Option<MacAddr>,
+        /// Any trailing text: the client's hostname in a DHCPACK, otherwise
+        /// an explanation such as `no address available`.
+        rest: Option<String>,
     }
 
-    fn ip_addr<'a, I>() -> impl Parser<Input = I, Output = String> + 'a
+    /// `(<iface>) [<ip>] [<mac>] [<text>]`, converted to a message by `make`,
+    /// which returns `None` if a required field is missing.
+    fn dnsmasq<'a, I>(make: fn(DnsmasqFields) -> Option<DhcpMsg>) -> impl Parser<Input = I, Output = DhcpMsg> + 'a
     where
         I: Stream<Item = u8, Range = &'a [u8]> + 'a,
         // Necessary due to rust-lang/rust#24159
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
         (
-            count_min_max::<Vec<u8>, _>(1, 3, digit()),
-            token(b'.'),
-            count_min_max::<Vec<u8>, _>(1, 3, digit()),
-            token(b'.'),
-            count_min_max::<Vec<u8>, _>(1, 3, digit()),
-            token(b'.'),
-            count_min_max::<Vec<u8>, _>(1, 3, digit()),
-        ).map(|(a, _, b, _, c, _, d)| {
-            let a = String::from_utf8(a).unwrap();
-            let b = String::from_utf8(b).unwrap();
-            let c = String::from_utf8(c).unwrap();
-            let d = String::from_utf8(d).unwrap();
-            format!("{}.{}.{}.{}", a, b, c, d)
+            token(b'('),
+            many1::<Vec<u8>, _>(satisfy(|c| c != b')')),
+            token(b')'),
+            optional(attempt(space().with(ip_addr()))),
+            optional(attempt(space().with(mac_addr()))),
+            optional(attempt(space().with(many1::<Vec<u8>, _>(satisfy(|c| c != b'\n'))))),
+        ).and_then(move |(_, via, _, ip_addr, mac_addr, rest)| {
+            let rest = rest
+                .and_then(|rest| String::from_utf8(rest).ok())
+                .map(|rest| rest.trim().to_string())
+                .filter(|rest| !rest.is_empty());
+            make(DnsmasqFields { via: String::from_utf8(via).ok(), ip_addr, mac_addr, rest })
+                .ok_or_else(|| StreamErrorFor::<I>::message_static_message("missing address in dnsmasq message"))
         })
     }
 
-    fn mac_addr<'a, I>() -> impl Parser<Input = I, Output = String> + 'a
+    /// `DHCPINFORM from <ip> via <iface>`
+    fn dhcp_inform<'a, I>() -> impl Parser<Input = I, Output = DhcpMsg> + 'a
     where
         I: Stream<Item = u8, Range = &'a [u8]> + 'a,
         // Necessary due to rust-lang/rust#24159
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
-        count_min_max::<Vec<u8>, _>(17, 17, satisfy(|c: u8| c.is_ascii_hexdigit() || c == b':')).and_then(|b| String::from_utf8(b).map_err(StreamErrorFor::<I>::other))
+        (
+            bytes(&b" from "[..]),
+            ip_addr(),
+            trailer(),
+        ).map(|(_, ip_addr, (via, reason))| DhcpMsg::Inform{ip_addr, via, reason})
     }
 
-    #[cfg(test)]
-    mod tests {
-        use super::{LogEntry, DhcpMsg};
-        use combine::Parser;
-        use chrono::naive::{NaiveDate, NaiveDateTime, NaiveTime};
+    /// `DHCPOFFER on <ip> to <mac> (<name>) via <iface>`
+    fn dhcp_offer<'a, I>() -> impl Parser<Input = I, Output = DhcpMsg> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        (
+            bytes(&b" on "[..]),
+            ip_addr(),
+            bytes(&b" to "[..]),
+            mac_addr(),
+            friendly_name(),
+            trailer(),
+        ).map(|(_, ip_addr, _, mac_addr, friendly_name, (via, reason))| DhcpMsg::Offer{ip_addr, mac_addr, friendly_name, via, reason})
+    }
 
-        #[test]
-        fn mac_addr() {
-            assert_eq!(
-                super::mac_addr().parse(&b"f4:ec:38:85:d8:a9"[..]),
-                Ok(("f4:ec:38:85:d8:a9".to_string(),  &b""[..]))
-            );
-        }
+    /// `DHCPACK on <ip> to <mac> (<name>) via <iface>` for leases, or
+    /// `DHCPACK to <ip> (<mac>) via <iface>` in reply to a DHCPINFORM.
+    fn dhcp_ack<'a, I>() -> impl Parser<Input = I, Output = DhcpMsg> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        let lease = (
+            bytes(&b"on "[..]),
+            ip_addr(),
+            bytes(&b" to "[..]),
+            mac_addr(),
+            friendly_name(),
+        ).map(|(_, ip_addr, _, mac_addr, friendly_name)| (ip_addr, mac_addr, friendly_name));
+        let inform = (
+            bytes(&b"to "[..]),
+            ip_addr(),
+            bytes(&b" ("[..]),
+            mac_addr(),
+            token(b')'),
+        ).map(|(_, ip_addr, _, mac_addr, _)| (ip_addr, mac_addr, None));
+        (
+            space(),
+            lease.or(inform),
+            trailer(),
+        ).map(|(_, (ip_addr, mac_addr, friendly_name), (via, reason))| DhcpMsg::Ack{ip_addr, mac_addr, friendly_name, client_id: None, lease_time: None, via, reason})
+    }
 
-        #[test]
+    /// `DHCPNAK on <ip> to <mac> via <iface>`
+    fn dhcp_nak<'a, I>() -> impl Parser<Input = I, Output = DhcpMsg> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        (
+            bytes(&b" on "[..]),
+            ip_addr(),
+            bytes(&b" to "[..]),
+            mac_addr(),
+            trailer(),
+        ).map(|(_, ip_addr, _, mac_addr, (via, reason))| DhcpMsg::Nak{ip_addr, mac_addr, via, reason})
+    }
+
+    /// `DHCPREQUEST for <ip> (<server ip>) from <mac> (<name>) via <iface>`
+    fn dhcp_request<'a, I>() -> impl Parser<Input = I, Output = DhcpMsg> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        (
+            bytes(&b" for "[..]),
+            ip_addr(),
+            optional(attempt(bytes(&b" ("[..])).with(ip_addr()).skip(token(b')'))),
+            bytes(&b" from "[..]),
+            mac_addr(),
+            friendly_name(),
+            trailer(),
+        ).map(|(_, ip_addr, server_ip, _, mac_addr, friendly_name, (via, reason))| DhcpMsg::Request{ip_addr, server_ip, mac_addr, friendly_name, via, reason})
+    }
+
+    /// `DHCPDISCOVER from <mac> (<name>) via <iface>`
+    fn dhcp_discover<'a, I>() -> impl Parser<Input = I, Output = DhcpMsg> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        (
+            bytes(&b" from "[..]),
+            mac_addr(),
+            friendly_name(),
+            trailer(),
+        ).map(|(_, mac_addr, friendly_name, (via, reason))| DhcpMsg::Discover{mac_addr, friendly_name, via, reason})
+    }
+
+    /// `DHCPRELEASE of <ip> from <mac> (<name>) via <iface> (found)`, or the
+    /// same for a DHCPDECLINE, which ends with `: <status>` instead.
+    fn dhcp_release<'a, I>() -> impl Parser<Input = I, Output = (Ipv4Addr, MacAddr, Option<String>, Option<String>)> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        (
+            bytes(&b" of "[..]),
+            ip_addr(),
+            bytes(&b" from "[..]),
+            mac_addr(),
+            friendly_name(),
+            trailer(),
+            optional(attempt(bytes(&b" ("[..])).with(many1::<Vec<u8>, _>(satisfy(|c| c != b')'))).skip(token(b')'))),
+        ).map(|(_, ip_addr, _, mac_addr, _, (via, reason), found)| {
+            let reason = reason.or_else(|| found.and_then(|found| String::from_utf8(found).ok()));
+            (ip_addr, mac_addr, via, reason)
+        })
+    }
+
+    /// The optional ` (<name>)` that follows a client's MAC address.
+    fn friendly_name<'a, I>() -> impl Parser<Input = I, Output = Option<String>> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        optional((
+            attempt(bytes(&b" ("[..])),
+            many1::<Vec<u8>, _>(satisfy(|c| c != b')')),
+            token(b')'),
+        )).map(|opt_name| opt_name.and_then(|(_, friendly_name, _)| String::from_utf8(friendly_name).ok()))
+    }
+
+    /// The optional ` via <iface>` and `: <reason>` that end a message.
+    fn trailer<'a, I>() -> impl Parser<Input = I, Output = (Option<String>, Option<String>)> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        (
+            optional(attempt(bytes(&b" via "[..])).with(many1::<Vec<u8>, _>(satisfy(|c| c != b' ' && c != b':' && c != b'\n')))),
+            optional(token(b':').with(many::<Vec<u8>, _>(satisfy(|c| c != b'\n')))),
+        ).map(|(opt_via, opt_reason): (Option<Vec<u8>>, Option<Vec<u8>>)| {
+            let via = opt_via.and_then(|via| String::from_utf8(via).ok());
+            let reason = opt_reason
+                .and_then(|reason| String::from_utf8(reason).ok())
+                .map(|reason| reason.trim().to_string());
+            (via, reason)
+        })
+    }
+
+    fn ip_addr<'a, I>() -> impl Parser<Input = I, Output = Ipv4Addr> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        let octet = || count_min_max::<Vec<u8>, _>(1, 3, digit()).and_then(|o| {
+            let o = String::from_utf8(o).map_err(StreamErrorFor::<I>::other)?;
+            o.parse::<u8>().map_err(|_| StreamErrorFor::<I>::message_message(format!("invalid IPv4 octet {}", o)))
+        });
+        (
+            octet(),
+            token(b'.'),
+            octet(),
+            token(b'.'),
+            octet(),
+            token(b'.'),
+            octet(),
+        ).map(|(a, _, b, _, c, _, d)| Ipv4Addr::new(a, b, c, d))
+    }
+
+    fn mac_addr<'a, I>() -> impl Parser<Input = I, Output = MacAddr> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        let byte = || count_min_max::<Vec<u8>, _>(2, 2, hex_digit()).and_then(|b| {
+            let b = String::from_utf8(b).map_err(StreamErrorFor::<I>::other)?;
+            u8::from_str_radix(&b, 16).map_err(StreamErrorFor::<I>::other)
+        });
+        (
+            byte(),
+            token(b':'),
+            byte(),
+            token(b':'),
+            byte(),
+            token(b':'),
+            byte(),
+            token(b':'),
+            byte(),
+            token(b':'),
+            byte(),
+        ).map(|(a, _, b, _, c, _, d, _, e, _, f)| MacAddr([a, b, c, d, e, f]))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{LogEntry, DhcpMsg};
+        use std::net::Ipv4Addr;
+        use {MacAddr, ParseStage, SyslogHeader};
+        use combine::Parser;
+        use chrono::{Duration, TimeZone, Utc};
+
+        #[test]
+        fn mac_addr() {
+            assert_eq!(
+                super::mac_addr().parse(&b"f4:ec:38:85:d8:a9"[..]),
+                Ok((MacAddr([0xf4, 0xec, 0x38, 0x85, 0xd8, 0xa9]), &b""[..]))
+            );
+            assert_eq!(
+                super::mac_addr().parse(&b"F4:EC:38:85:D8:A9"[..]),
+                Ok((MacAddr([0xf4, 0xec, 0x38, 0x85, 0xd8, 0xa9]), &b""[..]))
+            );
+            assert!(super::mac_addr().parse(&b":::::::::::::::::"[..]).is_err());
+        }
+
+        #[test]
+        fn ip_addr() {
+            assert_eq!(
+                super::ip_addr().parse(&b"192.168.0.254"[..]),
+                Ok((Ipv4Addr::new(192, 168, 0, 254), &b""[..]))
+            );
+            assert!(super::ip_addr().parse(&b"999.999.999.999"[..]).is_err());
+        }
+
+        #[test]
         fn dhcp_ack() {
             assert_eq!(
                 super::dhcp_ack().parse(&b" on 192.168.0.254 to a4:db:30:66:4f:90 "[..]),
-                Ok((DhcpMsg::Ack{ip_addr: "192.168.0.254".to_string(), mac_addr: "a4:db:30:66:4f:90".to_string(), friendly_name: None}, &b""[..]))
+                Ok((DhcpMsg::Ack{ip_addr: Ipv4Addr::new(192, 168, 0, 254), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: None, client_id: None, lease_time: None, via: None, reason: None}, &b" "[..]))
             );
             assert_eq!(
                 super::dhcp_ack().parse(&b" to 192.168.0.77 (9c:ad:97:d1:65:39) "[..]),
-                Ok((DhcpMsg::Ack{ip_addr: "192.168.0.77".to_string(), mac_addr: "9c:ad:97:d1:65:39".to_string(), friendly_name: None}, &b""[..]))
+                Ok((DhcpMsg::Ack{ip_addr: Ipv4Addr::new(192, 168, 0, 77), mac_addr: MacAddr([0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]), friendly_name: None, client_id: None, lease_time: None, via: None, reason: None}, &b" "[..]))
             );
         }
 
         #[test]
         fn dhcp_msg() {
             assert_eq!(
-                super::dhcp_msg().parse(&b"DHCPINFORM"[..]),
-                Ok((DhcpMsg::Inform, &b""[..]))
+                super::dhcp_msg().parse(&b"DHCPINFORM from 192.168.0.77 via eth0"[..]),
+                Ok((DhcpMsg::Inform{ip_addr: Ipv4Addr::new(192, 168, 0, 77), via: Some("eth0".to_string()), reason: None}, &b""[..]))
             );
             assert_eq!(
-                super::dhcp_msg().parse(&b"DHCPOFFER"[..]),
-                Ok((DhcpMsg::Offer, &b""[..]))
+                super::dhcp_msg().parse(&b"DHCPINFORM from 10.1.0.5 via 10.1.0.1: not authoritative for subnet 10.1.0.0"[..]),
+                Ok((DhcpMsg::Inform{ip_addr: Ipv4Addr::new(10, 1, 0, 5), via: Some("10.1.0.1".to_string()), reason: Some("not authoritative for subnet 10.1.0.0".to_string())}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPOFFER on 192.168.0.254 to a4:db:30:66:4f:90 (MyName) via eth0"[..]),
+                Ok((DhcpMsg::Offer{ip_addr: Ipv4Addr::new(192, 168, 0, 254), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: Some("MyName".to_string()), via: Some("eth0".to_string()), reason: None}, &b""[..]))
             );
             assert_eq!(
                 super::dhcp_msg().parse(&b"DHCPACK on 192.168.0.254 to a4:db:30:66:4f:90 via eth0"[..]),
-                Ok((DhcpMsg::Ack{ip_addr: "192.168.0.254".to_string(), mac_addr: "a4:db:30:66:4f:90".to_string(), friendly_name: None}, &b"via eth0"[..]))
+                Ok((DhcpMsg::Ack{ip_addr: Ipv4Addr::new(192, 168, 0, 254), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: None, client_id: None, lease_time: None, via: Some("eth0".to_string()), reason: None}, &b""[..]))
             );
             assert_eq!(
                 super::dhcp_msg().parse(&b"DHCPACK on 192.168.0.254 to a4:db:30:66:4f:90 (MyName) via eth0"[..]),
-                Ok((DhcpMsg::Ack{ip_addr: "192.168.0.254".to_string(), mac_addr: "a4:db:30:66:4f:90".to_string(), friendly_name: Some("MyName".to_string())}, &b" via eth0"[..]))
+                Ok((DhcpMsg::Ack{ip_addr: Ipv4Addr::new(192, 168, 0, 254), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: Some("MyName".to_string()), client_id: None, lease_time: None, via: Some("eth0".to_string()), reason: None}, &b""[..]))
             );
             assert_eq!(
                 super::dhcp_msg().parse(&b"DHCPACK to 192.168.0.77 (9c:ad:97:d1:65:39) via eth0"[..]),
-                Ok((DhcpMsg::Ack{ip_addr: "192.168.0.77".to_string(), mac_addr: "9c:ad:97:d1:65:39".to_string(), friendly_name: None}, &b"via eth0"[..]))
+                Ok((DhcpMsg::Ack{ip_addr: Ipv4Addr::new(192, 168, 0, 77), mac_addr: MacAddr([0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]), friendly_name: None, client_id: None, lease_time: None, via: Some("eth0".to_string()), reason: None}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPNAK on 10.0.0.5 to a4:db:30:66:4f:90 via eth0"[..]),
+                Ok((DhcpMsg::Nak{ip_addr: Ipv4Addr::new(10, 0, 0, 5), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), via: Some("eth0".to_string()), reason: None}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPREQUEST for 192.168.0.254 (192.168.0.1) from a4:db:30:66:4f:90 (MyName) via eth0"[..]),
+                Ok((DhcpMsg::Request{ip_addr: Ipv4Addr::new(192, 168, 0, 254), server_ip: Some(Ipv4Addr::new(192, 168, 0, 1)), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: Some("MyName".to_string()), via: Some("eth0".to_string()), reason: None}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPREQUEST for 10.0.0.5 from a4:db:30:66:4f:90 via eth0: wrong network."[..]),
+                Ok((DhcpMsg::Request{ip_addr: Ipv4Addr::new(10, 0, 0, 5), server_ip: None, mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: None, via: Some("eth0".to_string()), reason: Some("wrong network.".to_string())}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPDISCOVER from a4:db:30:66:4f:90 via eth0: network 192.168.0.0/24: no free leases"[..]),
+                Ok((DhcpMsg::Discover{mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: None, via: Some("eth0".to_string()), reason: Some("network 192.168.0.0/24: no free leases".to_string())}, &b""[..]))
+            );
+        }
+
+        #[test]
+        fn dhcp_release() {
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPRELEASE of 192.168.0.77 from 9c:ad:97:d1:65:39 (Joe) via eth0 (found)"[..]),
+                Ok((DhcpMsg::Release{ip_addr: Ipv4Addr::new(192, 168, 0, 77), mac_addr: MacAddr([0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]), via: Some("eth0".to_string()), reason: Some("found".to_string())}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPDECLINE of 192.168.0.77 from 9c:ad:97:d1:65:39 via eth0: abandoned"[..]),
+                Ok((DhcpMsg::Decline{ip_addr: Ipv4Addr::new(192, 168, 0, 77), mac_addr: MacAddr([0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]), via: Some("eth0".to_string()), reason: Some("abandoned".to_string())}, &b""[..]))
+            );
+        }
+
+        #[test]
+        fn dnsmasq_msg() {
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPDISCOVER(br0) a4:db:30:66:4f:90"[..]),
+                Ok((DhcpMsg::Discover{mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: None, via: Some("br0".to_string()), reason: None}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPDISCOVER(br0) 192.168.1.5 a4:db:30:66:4f:90 no address available"[..]),
+                Ok((DhcpMsg::Discover{mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: None, via: Some("br0".to_string()), reason: Some("no address available".to_string())}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPOFFER(br0) 192.168.1.5 a4:db:30:66:4f:90"[..]),
+                Ok((DhcpMsg::Offer{ip_addr: Ipv4Addr::new(192, 168, 1, 5), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: None, via: Some("br0".to_string()), reason: None}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPREQUEST(br0) 192.168.1.5 a4:db:30:66:4f:90"[..]),
+                Ok((DhcpMsg::Request{ip_addr: Ipv4Addr::new(192, 168, 1, 5), server_ip: None, mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: None, via: Some("br0".to_string()), reason: None}, &b""[..]))
             );
             assert_eq!(
-                super::dhcp_msg().parse(&b"DHCPNAK"[..]),
-                Ok((DhcpMsg::Nak, &b""[..]))
+                super::dhcp_msg().parse(&b"1234567 DHCPACK(br0) 192.168.1.5 a4:db:30:66:4f:90 MyName"[..]),
+                Ok((DhcpMsg::Ack{ip_addr: Ipv4Addr::new(192, 168, 1, 5), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: Some("MyName".to_string()), client_id: None, lease_time: None, via: Some("br0".to_string()), reason: None}, &b""[..]))
             );
             assert_eq!(
-                super::dhcp_msg().parse(&b"DHCPREQUEST"[..]),
-                Ok((DhcpMsg::Request, &b""[..]))
+                super::dhcp_msg().parse(&b"DHCPNAK(br0) 192.168.1.5 a4:db:30:66:4f:90 wrong address"[..]),
+                Ok((DhcpMsg::Nak{ip_addr: Ipv4Addr::new(192, 168, 1, 5), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), via: Some("br0".to_string()), reason: Some("wrong address".to_string())}, &b""[..]))
             );
             assert_eq!(
-                super::dhcp_msg().parse(&b"DHCPDISCOVER"[..]),
-                Ok((DhcpMsg::Discover, &b""[..]))
+                super::dhcp_msg().parse(&b"DHCPINFORM(br0) 192.168.1.5 a4:db:30:66:4f:90"[..]),
+                Ok((DhcpMsg::Inform{ip_addr: Ipv4Addr::new(192, 168, 1, 5), via: Some("br0".to_string()), reason: None}, &b""[..]))
             );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPRELEASE(br0) 192.168.1.5 a4:db:30:66:4f:90"[..]),
+                Ok((DhcpMsg::Release{ip_addr: Ipv4Addr::new(192, 168, 1, 5), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), via: Some("br0".to_string()), reason: None}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DHCPDECLINE(br0) 192.168.1.5 a4:db:30:66:4f:90"[..]),
+                Ok((DhcpMsg::Decline{ip_addr: Ipv4Addr::new(192, 168, 1, 5), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), via: Some("br0".to_string()), reason: None}, &b""[..]))
+            );
+            assert!(super::dhcp_msg().parse(&b"DHCPACK(br0) a4:db:30:66:4f:90"[..]).is_err());
+        }
+
+        #[test]
+        fn kea_msg() {
+            assert_eq!(
+                super::dhcp_msg().parse(&b"INFO  [kea-dhcp4.leases/1923.140372] DHCP4_LEASE_ALLOC [hwtype=1 a4:db:30:66:4f:90], cid=[01:a4:db:30:66:4f:90], tid=0x5b6a1c3e: lease 10.0.0.5 has been allocated for 3600 seconds"[..]),
+                Ok((DhcpMsg::Ack{ip_addr: Ipv4Addr::new(10, 0, 0, 5), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: None, client_id: Some(vec![0x01, 0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), lease_time: Some(3600), via: None, reason: None}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"INFO  [kea-dhcp4.leases/1923.140372] DHCP4_LEASE_ADVERT [hwtype=1 a4:db:30:66:4f:90], cid=[no info], tid=0x5b6a1c3e: lease 10.0.0.5 will be advertised"[..]),
+                Ok((DhcpMsg::Offer{ip_addr: Ipv4Addr::new(10, 0, 0, 5), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), friendly_name: None, via: None, reason: None}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"INFO  [kea-dhcp4.leases/1923.140372] DHCP4_RELEASE [hwtype=1 a4:db:30:66:4f:90], cid=[no info], tid=0x7d2e: address 10.0.0.5 was released properly."[..]),
+                Ok((DhcpMsg::Release{ip_addr: Ipv4Addr::new(10, 0, 0, 5), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), via: None, reason: None}, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp_msg().parse(&b"DEBUG [kea-dhcp4.alloc-engine/1923.140372] ALLOC_ENGINE_V4_LEASE_RECLAIM [hwtype=1 a4:db:30:66:4f:90], cid=[no info]: reclaiming expired lease for address 10.0.0.5"[..]),
+                Ok((DhcpMsg::Expire{ip_addr: Ipv4Addr::new(10, 0, 0, 5), mac_addr: MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]), via: None, reason: None}, &b""[..]))
+            );
+            assert!(super::dhcp_msg().parse(&b"INFO  [kea-dhcp4.packets/1923.140372] DHCP4_PACKET_RECEIVED [hwtype=1 a4:db:30:66:4f:90], cid=[no info], tid=0x7d2e: DHCPDISCOVER (type 1) received"[..]).is_err());
         }
 
         #[test]
         fn log_entry() {
             let log = &br#"2015:06:03-00:01:00 PublicWiFi dhcpd: DHCPACK to 192.168.0.77 (9c:ad:97:d1:65:39) "#[..];
             let want = LogEntry {
-                datetime: NaiveDateTime::new(
-                    NaiveDate::from_ymd(2015, 6, 3),
-                    NaiveTime::from_hms(0, 1, 0),
-                ),
-                msg: DhcpMsg::Ack{ip_addr: "192.168.0.77".to_string(), mac_addr: "9c:ad:97:d1:65:39".to_string(), friendly_name: None}
+                datetime: Utc.with_ymd_and_hms(2015, 6, 3, 0, 1, 0).unwrap().fixed_offset(),
+                header: SyslogHeader { host: "PublicWiFi".to_string(), program: "dhcpd".to_string(), pid: None },
+                msg: DhcpMsg::Ack{ip_addr: Ipv4Addr::new(192, 168, 0, 77), mac_addr: MacAddr([0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]), friendly_name: None, client_id: None, lease_time: None, via: None, reason: None}
+            };
+            assert_eq!(LogEntry::new(log), Ok(want));
+
+            let log = &br#"2015-06-03T00:01:00Z router dnsmasq-dhcp[812]: 3021447311 DHCPACK(br0) 192.168.0.77 9c:ad:97:d1:65:39 Joe"#[..];
+            let want = LogEntry {
+                datetime: Utc.with_ymd_and_hms(2015, 6, 3, 0, 1, 0).unwrap().fixed_offset(),
+                header: SyslogHeader { host: "router".to_string(), program: "dnsmasq-dhcp".to_string(), pid: Some(812) },
+                msg: DhcpMsg::Ack{ip_addr: Ipv4Addr::new(192, 168, 0, 77), mac_addr: MacAddr([0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]), friendly_name: Some("Joe".to_string()), client_id: None, lease_time: None, via: Some("br0".to_string()), reason: None}
+            };
+            assert_eq!(LogEntry::new(log), Ok(want));
+
+            let log = &br#"2015-06-03T00:01:00Z dhcp1 kea-dhcp4[1923]: INFO  [kea-dhcp4.leases/1923.140372] DHCP4_LEASE_ALLOC [hwtype=1 9c:ad:97:d1:65:39], cid=[no info], tid=0x5b6a1c3e: lease 192.168.0.77 has been allocated for 3600 seconds"#[..];
+            let want = LogEntry {
+                datetime: Utc.with_ymd_and_hms(2015, 6, 3, 0, 1, 0).unwrap().fixed_offset(),
+                header: SyslogHeader { host: "dhcp1".to_string(), program: "kea-dhcp4".to_string(), pid: Some(1923) },
+                msg: DhcpMsg::Ack{ip_addr: Ipv4Addr::new(192, 168, 0, 77), mac_addr: MacAddr([0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]), friendly_name: None, client_id: None, lease_time: Some(3600), via: None, reason: None}
+            };
+            assert_eq!(LogEntry::new(log), Ok(want));
+
+            // Kea's own log file, in local time.
+            let log = &br#"2019-05-01 12:00:00.123 INFO  [kea-dhcp4.leases/1234.5678] DHCP4_LEASE_ALLOC [hwtype=1 9c:ad:97:d1:65:39], cid=[01:9c:ad:97:d1:65:39], tid=0x2f0a1b3c: lease 192.168.0.77 has been allocated for 4000 seconds"#[..];
+            let want = LogEntry {
+                datetime: Utc.with_ymd_and_hms(2019, 5, 1, 12, 0, 0).unwrap().fixed_offset() + Duration::milliseconds(123),
+                header: SyslogHeader { host: String::new(), program: "kea-dhcp4".to_string(), pid: Some(1234) },
+                msg: DhcpMsg::Ack{ip_addr: Ipv4Addr::new(192, 168, 0, 77), mac_addr: MacAddr([0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]), friendly_name: None, client_id: Some(vec![0x01, 0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]), lease_time: Some(4000), via: None, reason: None}
             };
-            assert_eq!(super::log_entry().parse(log), Ok((want, &b""[..])));
+            assert_eq!(LogEntry::new(log), Ok(want));
+        }
+
+        #[test]
+        fn log_entry_error() {
+            let err = LogEntry::new(&b"2015:06:32-00:01:00 PublicWiFi dhcpd: DHCPACK"[..]).unwrap_err();
+            assert_eq!(err.stage, ParseStage::Datetime);
+            assert_eq!(err.offset, 0);
+
+            let err = LogEntry::new(&b"2015:06:03-00:01:00 PublicWiFi dhcpd"[..]).unwrap_err();
+            assert_eq!(err.stage, ParseStage::ProgramTag);
+            assert_eq!(err.offset, 36);
+            assert_eq!(err.found, Some("end of input".to_string()));
 
+            let err = LogEntry::new(&b"2015:06:03-00:01:00 PublicWiFi dhcpd: DHCPBOGUS"[..]).unwrap_err();
+            assert_eq!(err.stage, ParseStage::DhcpMsg);
+            assert_eq!(err.offset, 42);
+            assert_eq!(err.found, Some("'B'".to_string()));
+            assert!(err.expected.contains(&"\"ACK\"".to_string()));
+
+            let err = LogEntry::new(&b"2015:06:03-00:01:00 PublicWiFi dhcpd: DHCPACK on 192.168.0.256 to a4:db:30:66:4f:90"[..]).unwrap_err();
+            assert_eq!(err.stage, ParseStage::DhcpMsg);
+            assert_eq!(err.messages, vec!["invalid IPv4 octet 256".to_string()]);
         }
     }
 }
 
-pub mod http {
+pub mod dhcp6 {
+    use chrono::{DateTime, FixedOffset};
+    use {MacAddr, ParseStage, SyslogHeader, TimestampOptions};
     use combine::{
-        between, eof, many, many1, satisfy, token, Parser, Stream,
-        error::ParseError,
-        parser::byte::{newline, space}};
-    use std::collections::HashMap;
-    use chrono::NaiveDateTime;
+        optional, many, many1, satisfy, skip_many1, token, Parser, Stream, attempt, choice,
+        error::{ParseError, StreamError},
+        stream::StreamErrorFor,
+        parser::byte::{digit, space, bytes}};
+    use std::fmt;
+    use std::net::Ipv6Addr;
 
     #[derive(Debug, PartialEq, Clone)]
     pub struct LogEntry {
-        pub datetime: NaiveDateTime,
-        pub attrs: HashMap<String, Vec<u8>>,
+        pub datetime: DateTime<FixedOffset>,
+        pub header: SyslogHeader,
+        pub msg: Dhcp6Msg,
+    }
+
+    /// A DHCPv6 message logged by ISC dhcpd in `-6` mode or by dnsmasq.
+    ///
+    /// The two servers log different details for each message, so every field
+    /// other than the type is optional.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct Dhcp6Msg {
+        pub msg_type: MsgType,
+        /// The client's link-local address that the message came from or was
+        /// sent to.
+        pub link_addr: Option<Ipv6Addr>,
+        /// The address being offered or assigned to the client.
+        pub ip_addr: Option<Ipv6Addr>,
+        pub duid: Option<Duid>,
+        pub hostname: Option<String>,
+        pub interface: Option<String>,
+        /// The valid lifetime of `ip_addr` in seconds.
+        pub lease_time: Option<u32>,
+    }
+
+    #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+    pub enum MsgType {
+        Solicit,
+        Advertise,
+        Request,
+        Confirm,
+        Renew,
+        Rebind,
+        Reply,
+        Release,
+        Decline,
+        Reconfigure,
+        InformationRequest,
+    }
+
+    impl MsgType {
+        /// The message type as named in RFC 8415, e.g. `Reply`.
+        pub fn name(&self) -> &'static str {
+            match *self {
+                MsgType::Solicit => "Solicit",
+                MsgType::Advertise => "Advertise",
+                MsgType::Request => "Request",
+                MsgType::Confirm => "Confirm",
+                MsgType::Renew => "Renew",
+                MsgType::Rebind => "Rebind",
+                MsgType::Reply => "Reply",
+                MsgType::Release => "Release",
+                MsgType::Decline => "Decline",
+                MsgType::Reconfigure => "Reconfigure",
+                MsgType::InformationRequest => "Information-request",
+            }
+        }
+
+        fn from_name(name: &str) -> Option<Self> {
+            Some(match &name.to_ascii_lowercase()[..] {
+                "solicit" => MsgType::Solicit,
+                "advertise" => MsgType::Advertise,
+                "request" => MsgType::Request,
+                "confirm" => MsgType::Confirm,
+                "renew" => MsgType::Renew,
+                "rebind" => MsgType::Rebind,
+                "reply" => MsgType::Reply,
+                "release" => MsgType::Release,
+                "decline" => MsgType::Decline,
+                "reconfigure" => MsgType::Reconfigure,
+                "information-request" => MsgType::InformationRequest,
+                _ => return None,
+            })
+        }
+    }
+
+    /// A DHCP Unique Identifier, which DHCPv6 uses in place of the client's
+    /// hardware address.
+    #[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+    pub struct Duid(pub Vec<u8>);
+
+    impl Duid {
+        /// Parses the colon-separated hex bytes that servers log DUIDs as.
+        fn from_hex(s: &str) -> Option<Self> {
+            let bytes = s.split(':').map(|b| {
+                if b.len() == 2 && b.bytes().all(|c| c.is_ascii_hexdigit()) {
+                    u8::from_str_radix(b, 16).ok()
+                } else {
+                    None
+                }
+            }).collect::<Option<Vec<u8>>>()?;
+            if bytes.len() < 4 {
+                return None;
+            }
+            Some(Duid(bytes))
+        }
+
+        /// The Ethernet address embedded in a DUID-LLT or DUID-LL, if any.
+        pub fn mac_addr(&self) -> Option<MacAddr> {
+            let lladdr = match (self.0.get(..2)?, self.0.get(2..4)?) {
+                ([0, 1], [0, 1]) => self.0.get(8..)?,
+                ([0, 3], [0, 1]) => &self.0[4..],
+                _ => return None,
+            };
+            if lladdr.len() != 6 {
+                return None;
+            }
+            let mut mac = [0u8; 6];
+            mac.copy_from_slice(lladdr);
+            Some(MacAddr(mac))
+        }
+    }
+
+    impl fmt::Display for Duid {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            for (i, b) in self.0.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(":")?;
+                }
+                write!(f, "{:02x}", b)?;
+            }
+            Ok(())
+        }
     }
 
     impl LogEntry {
-        pub fn new(s: &[u8]) -> Result<Self, Box<::std::error::Error>> {
-            log_entry()
-                .easy_parse(s)
-                .map(|x| x.0)
-                .map_err(|_| "error".into())
+        pub fn new(s: &[u8]) -> Result<Self, ::ParseError> {
+            Self::with_options(s, &mut TimestampOptions::default())
+        }
+
+        pub fn with_options(s: &[u8], options: &mut TimestampOptions) -> Result<Self, ::ParseError> {
+            let (datetime, rest) = ::parse_stage(s, s, ParseStage::Datetime, ::timestamp(options.reference))?;
+            let datetime = options.resolve(datetime)?;
+            let (header, rest) = ::parse_stage(s, rest, ParseStage::ProgramTag, ::syslog_header())?;
+            let (msg, _) = ::parse_stage(s, rest, ParseStage::Dhcp6Msg, dhcp6_msg())?;
+            Ok(LogEntry { datetime, header, msg })
         }
     }
 
-    fn log_entry<'a, I>() -> impl Parser<Input = I, Output = LogEntry> + 'a
+    fn dhcp6_msg<'a, I>() -> impl Parser<Input = I, Output = Dhcp6Msg> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        choice((
+            attempt(bytes(&b"Sending "[..])).with(isc_sending()),
+            dnsmasq_msg(),
+            isc_msg(),
+        ))
+    }
+
+    fn empty_msg(msg_type: MsgType) -> Dhcp6Msg {
+        Dhcp6Msg { msg_type, link_addr: None, ip_addr: None, duid: None, hostname: None, interface: None, lease_time: None }
+    }
+
+    /// `Sending <type> to <link addr> port <port>`
+    fn isc_sending<'a, I>() -> impl Parser<Input = I, Output = Dhcp6Msg> + 'a
     where
         I: Stream<Item = u8, Range = &'a [u8]> + 'a,
         // Necessary due to rust-lang/rust#24159
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
         (
-            ::datetime(),
-            many1::<Vec<u8>, _>(satisfy(|c| c != b':')),
-            token(b':'),
-            space(),
-            attrs(),
-        ).map(|(datetime, _, _, _, attrs)| LogEntry { datetime, attrs })
+            msg_type(),
+            bytes(&b" to "[..]),
+            ip6_addr(),
+        ).map(|(msg_type, _, link_addr)| Dhcp6Msg { link_addr: Some(link_addr), ..empty_msg(msg_type) })
     }
 
-    fn attr<'a, I>() -> impl Parser<Input = I, Output = (String, Vec<u8>)> + 'a
+    /// `<type> message from <link addr> port <port>, transaction ID <xid>` or
+    /// `<type> NA: address <ip> to client with duid <duid> iaid = <iaid> ...`
+    fn isc_msg<'a, I>() -> impl Parser<Input = I, Output = Dhcp6Msg> + 'a
     where
         I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
+        let received = attempt(bytes(&b" message from "[..]))
+            .with(ip6_addr())
+            .map(|link_addr| (Some(link_addr), None, None, None));
+        let lease_time = (
+            attempt(bytes(&b" iaid = "[..])),
+            skip_many1(digit()),
+            bytes(&b" valid for "[..]),
+            many1::<String, _>(digit().map(char::from)).and_then(|secs| secs.parse::<u32>().map_err(StreamErrorFor::<I>::other)),
+            bytes(&b" seconds"[..]),
+        ).map(|(_, _, _, secs, _)| secs);
+        let assigned = (
+            attempt(bytes(&b" NA: address "[..])),
+            ip6_addr(),
+            bytes(&b" to client with duid "[..]),
+            duid(),
+            optional(lease_time),
+        ).map(|(_, ip_addr, _, duid, lease_time)| (None, Some(ip_addr), Some(duid), lease_time));
         (
-            many1::<Vec<u8>, _>(satisfy(|c| c != b'=')),
-            token(b'='),
-            between(token(b'"'), token(b'"'), many(satisfy(|c| c != b'"'))),
-        ).map(|(k, _, v)| {
-            let k = String::from_utf8(k).unwrap();
-            (k, v)
+            msg_type(),
+            received.or(assigned),
+        ).map(|(msg_type, (link_addr, ip_addr, duid, lease_time))| Dhcp6Msg { link_addr, ip_addr, duid, lease_time, ..empty_msg(msg_type) })
+    }
+
+    /// `[<xid> ]DHCP<TYPE>(<iface>) [<ip> ]<duid>[ <hostname>]`
+    fn dnsmasq_msg<'a, I>() -> impl Parser<Input = I, Output = Dhcp6Msg> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        (
+            optional(attempt(many1::<Vec<u8>, _>(digit()).skip(space()))),
+            attempt(bytes(&b"DHCP"[..])),
+            msg_type(),
+            token(b'('),
+            many1::<Vec<u8>, _>(satisfy(|c| c != b')')),
+            token(b')'),
+            many::<Vec<_>, _>(attempt(space().with(many1::<Vec<u8>, _>(satisfy(|c| c != b' ' && c != b'\n'))))),
+        ).map(|(_, _, msg_type, _, interface, _, words)| {
+            let mut words = words.into_iter().filter_map(|w| String::from_utf8(w).ok()).peekable();
+            let ip_addr = match words.peek() {
+                Some(w) if Duid::from_hex(w).is_none() => w.parse::<Ipv6Addr>().ok(),
+                _ => None,
+            };
+            if ip_addr.is_some() {
+                words.next();
+            }
+            let duid = words.peek().and_then(|w| Duid::from_hex(w));
+            if duid.is_some() {
+                words.next();
+            }
+            let rest: Vec<String> = words.collect();
+            let hostname = if rest.len() == 1 { rest.into_iter().next() } else { None };
+            Dhcp6Msg {
+                ip_addr,
+                duid,
+                hostname,
+                interface: String::from_utf8(interface).ok(),
+                ..empty_msg(msg_type)
+            }
         })
     }
 
-    fn attrs<'a, I>() -> impl Parser<Input = I, Output = HashMap<String, Vec<u8>>> + 'a
+    fn msg_type<'a, I>() -> impl Parser<Input = I, Output = MsgType> + 'a
     where
         I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
-        let eol = || newline().or(eof().map(|_| b' '));
-        many(attr().skip(space().or(eol())))
+        many1::<Vec<u8>, _>(satisfy(|c: u8| c.is_ascii_alphabetic() || c == b'-')).and_then(|name| {
+            let name = String::from_utf8(name).map_err(StreamErrorFor::<I>::other)?;
+            MsgType::from_name(&name).ok_or_else(|| StreamErrorFor::<I>::message_message(format!("unknown DHCPv6 message type {}", name)))
+        })
+    }
+
+    fn ip6_addr<'a, I>() -> impl Parser<Input = I, Output = Ipv6Addr> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        many1::<Vec<u8>, _>(satisfy(|c: u8| c.is_ascii_hexdigit() || c == b':' || c == b'.')).and_then(|addr| {
+            let addr = String::from_utf8(addr).map_err(StreamErrorFor::<I>::other)?;
+            addr.parse().map_err(|_| StreamErrorFor::<I>::message_message(format!("invalid IPv6 address {}", addr)))
+        })
+    }
+
+    fn duid<'a, I>() -> impl Parser<Input = I, Output = Duid> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        many1::<Vec<u8>, _>(satisfy(|c: u8| c.is_ascii_hexdigit() || c == b':')).and_then(|duid| {
+            let duid = String::from_utf8(duid).map_err(StreamErrorFor::<I>::other)?;
+            Duid::from_hex(&duid).ok_or_else(|| StreamErrorFor::<I>::message_message(format!("invalid DUID {}", duid)))
+        })
     }
 
     #[cfg(test)]
     mod tests {
-        use super::LogEntry;
+        use super::{Dhcp6Msg, Duid, LogEntry, MsgType};
+        use std::net::Ipv6Addr;
+        use {MacAddr, SyslogHeader};
         use combine::Parser;
-        use std::collections::HashMap;
-        use chrono::naive::{NaiveDate, NaiveDateTime, NaiveTime};
-        #[test]
-        fn attr() {
-            assert_eq!(
-                super::attr().parse(&b"foo=\"bar\""[..]),
-                Ok((("foo".to_string(), b"bar"[..].to_vec()), &b""[..]))
-            );
+        use chrono::{TimeZone, Utc};
+
+        fn duid() -> Duid {
+            Duid(vec![0x00, 0x01, 0x00, 0x01, 0x1d, 0x2c, 0x3a, 0x4b, 0x00, 0x1a, 0xa0, 0x12, 0x34, 0x56])
         }
 
         #[test]
-        fn attrs() {
-            let want: HashMap<String, Vec<u8>> = [
-                ("foo".to_string(), b"bar"[..].to_vec()),
-                ("bat".to_string(), b"baz"[..].to_vec()),
-            ].iter()
-                .cloned()
-                .collect();
-            assert_eq!(
-                super::attrs().parse(&b"foo=\"bar\" bat=\"baz\""[..]),
-                Ok((want, &b""[..]))
-            );
+        fn duid_mac_addr() {
+            assert_eq!(duid().mac_addr(), Some(MacAddr([0x00, 0x1a, 0xa0, 0x12, 0x34, 0x56])));
+            assert_eq!(Duid(vec![0x00, 0x03, 0x00, 0x01, 0x00, 0x1a, 0xa0, 0x12, 0x34, 0x56]).mac_addr(), Some(MacAddr([0x00, 0x1a, 0xa0, 0x12, 0x34, 0x56])));
+            assert_eq!(Duid(vec![0x00, 0x02, 0x00, 0x00, 0x00, 0x09, 0x0c, 0xc0]).mac_addr(), None);
+            assert_eq!(duid().to_string(), "00:01:00:01:1d:2c:3a:4b:00:1a:a0:12:34:56");
         }
 
         #[test]
-        fn date() {
+        fn dhcp6_msg() {
+            let link_addr: Ipv6Addr = "fe80::21a:a0ff:fe12:3456".parse().unwrap();
+            let ip_addr: Ipv6Addr = "2001:db8::100".parse().unwrap();
+            assert_eq!(
+                super::dhcp6_msg().parse(&b"Solicit message from fe80::21a:a0ff:fe12:3456 port 546, transaction ID 0x2D4B7700"[..]),
+                Ok((Dhcp6Msg { link_addr: Some(link_addr), ..super::empty_msg(MsgType::Solicit) }, &b" port 546, transaction ID 0x2D4B7700"[..]))
+            );
+            assert_eq!(
+                super::dhcp6_msg().parse(&b"Reply NA: address 2001:db8::100 to client with duid 00:01:00:01:1d:2c:3a:4b:00:1a:a0:12:34:56 iaid = 2843839090 valid for 43200 seconds"[..]),
+                Ok((Dhcp6Msg { ip_addr: Some(ip_addr), duid: Some(duid()), lease_time: Some(43200), ..super::empty_msg(MsgType::Reply) }, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp6_msg().parse(&b"Sending Advertise to fe80::21a:a0ff:fe12:3456 port 546"[..]),
+                Ok((Dhcp6Msg { link_addr: Some(link_addr), ..super::empty_msg(MsgType::Advertise) }, &b" port 546"[..]))
+            );
+            assert_eq!(
+                super::dhcp6_msg().parse(&b"1234567 DHCPREPLY(eth0) 2001:db8::100 00:01:00:01:1d:2c:3a:4b:00:1a:a0:12:34:56 myhost"[..]),
+                Ok((Dhcp6Msg { ip_addr: Some(ip_addr), duid: Some(duid()), hostname: Some("myhost".to_string()), interface: Some("eth0".to_string()), ..super::empty_msg(MsgType::Reply) }, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp6_msg().parse(&b"DHCPSOLICIT(eth0) 00:01:00:01:1d:2c:3a:4b:00:1a:a0:12:34:56"[..]),
+                Ok((Dhcp6Msg { duid: Some(duid()), interface: Some("eth0".to_string()), ..super::empty_msg(MsgType::Solicit) }, &b""[..]))
+            );
+            assert_eq!(
+                super::dhcp6_msg().parse(&b"DHCPINFORMATION-REQUEST(eth0) 00:01:00:01:1d:2c:3a:4b:00:1a:a0:12:34:56"[..]),
+                Ok((Dhcp6Msg { duid: Some(duid()), interface: Some("eth0".to_string()), ..super::empty_msg(MsgType::InformationRequest) }, &b""[..]))
+            );
+        }
+
+        #[test]
+        fn log_entry() {
+            let log = &b"2016:04:03-23:59:59 PublicWiFi dnsmasq-dhcp[1234]: DHCPREPLY(eth0) 2001:db8::100 00:01:00:01:1d:2c:3a:4b:00:1a:a0:12:34:56"[..];
+            let want = LogEntry {
+                datetime: Utc.with_ymd_and_hms(2016, 4, 3, 23, 59, 59).unwrap().fixed_offset(),
+                header: SyslogHeader { host: "PublicWiFi".to_string(), program: "dnsmasq-dhcp".to_string(), pid: Some(1234) },
+                msg: Dhcp6Msg {
+                    ip_addr: Some("2001:db8::100".parse().unwrap()),
+                    duid: Some(duid()),
+                    interface: Some("eth0".to_string()),
+                    ..super::empty_msg(MsgType::Reply)
+                },
+            };
+            assert_eq!(LogEntry::new(log), Ok(want));
+        }
+    }
+}
+
+/// The lease database ISC dhcpd keeps in `dhcpd.leases`.
+pub mod leases {
+    use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+    use {MacAddr, ParseStage};
+    use combine::{
+        between, eof, many, many1, satisfy, skip_many, skip_many1, token, Parser, Stream, attempt, choice, count_min_max,
+        error::{ParseError, StreamError},
+        stream::StreamErrorFor,
+        parser::byte::{space, bytes}};
+    use std::net::Ipv4Addr;
+
+    /// A `lease <ip> { ... }` declaration.
+    ///
+    /// dhcpd appends a new declaration whenever a lease changes, so a file
+    /// usually holds several for the same address and the last one is current.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct Lease {
+        pub ip_addr: Ipv4Addr,
+        pub starts: Option<DateTime<FixedOffset>>,
+        /// `None` if the lease never ends or no end was recorded.
+        pub ends: Option<DateTime<FixedOffset>>,
+        pub binding_state: Option<String>,
+        pub mac_addr: Option<MacAddr>,
+        pub hostname: Option<String>,
+        /// The DHCP client identifier from the `uid` statement.
+        pub client_id: Option<Vec<u8>>,
+    }
+
+    /// Parses the contents of a `dhcpd.leases` file, skipping everything
+    /// other than lease declarations.
+    pub fn parse(s: &[u8]) -> Result<Vec<Lease>, ::ParseError> {
+        let (leases, _) = ::parse_stage(s, s, ParseStage::Lease, leases())?;
+        Ok(leases)
+    }
+
+    fn leases<'a, I>() -> impl Parser<Input = I, Output = Vec<Lease>> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        (
+            skip_ws(),
+            many::<Vec<_>, _>(lease().map(Some).or(statement().map(|_| None))),
+            eof(),
+        ).map(|(_, leases, _)| leases.into_iter().flatten().collect())
+    }
+
+    /// `lease <ip> { <statement>... }`
+    fn lease<'a, I>() -> impl Parser<Input = I, Output = Lease> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        (
+            attempt(bytes(&b"lease"[..]).skip(skip_many1(space()))),
+            word().map(|word| String::from_utf8_lossy(&word).into_owned()),
+            token(b'{').skip(skip_ws()),
+            many::<Vec<_>, _>(statement()),
+            token(b'}').skip(skip_ws()),
+        ).and_then(|(_, ip_addr, _, statements, _)| -> Result<_, StreamErrorFor<I>> {
+            let ip_addr = ip_addr.parse::<Ipv4Addr>()
+                .map_err(|_| StreamErrorFor::<I>::message_message(format!("invalid lease address {}", ip_addr)))?;
+            let mut lease = Lease { ip_addr, starts: None, ends: None, binding_state: None, mac_addr: None, hostname: None, client_id: None };
+            for raw in statements {
+                let words: Vec<String> = raw.iter().map(|word| String::from_utf8_lossy(word).into_owned()).collect();
+                let words: Vec<&str> = words.iter().map(String::as_str).collect();
+                match words.as_slice() {
+                    ["starts", time @ ..] => lease.starts = lease_time(time).map_err(StreamErrorFor::<I>::message_message)?,
+                    ["ends", time @ ..] => lease.ends = lease_time(time).map_err(StreamErrorFor::<I>::message_message)?,
+                    ["binding", "state", state] => lease.binding_state = Some(state.to_string()),
+                    ["hardware", "ethernet", mac_addr] => {
+                        lease.mac_addr = Some(mac_addr.parse().map_err(|_| StreamErrorFor::<I>::message_message(format!("invalid hardware address {}", mac_addr)))?);
+                    },
+                    ["client-hostname", hostname] => lease.hostname = Some(hostname.to_string()),
+                    ["uid", _] => lease.client_id = Some(uid(&raw[1])),
+                    _ => {},
+                }
+            }
+            Ok(lease)
+        })
+    }
+
+    /// dhcpd writes a `uid` either as a quoted string or, when it has no
+    /// printable form, as colon separated hex bytes.
+    fn uid(word: &[u8]) -> Vec<u8> {
+        ::std::str::from_utf8(word).ok()
+            .and_then(|word| word.split(':')
+                .map(|byte| if byte.len() == 2 { u8::from_str_radix(byte, 16).ok() } else { None })
+                .collect::<Option<Vec<u8>>>())
+            .filter(|bytes| bytes.len() > 1)
+            .unwrap_or_else(|| word.to_vec())
+    }
+
+    /// `<weekday> <yyyy/mm/dd> <hh:mm:ss>` in UTC, `epoch <seconds>`, or
+    /// `never`.
+    fn lease_time(words: &[&str]) -> Result<Option<DateTime<FixedOffset>>, String> {
+        match *words {
+            ["never"] => Ok(None),
+            ["epoch", secs] => secs.parse::<i64>().ok()
+                .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+                .map(|datetime| Some(datetime.fixed_offset()))
+                .ok_or_else(|| format!("invalid lease time epoch {}", secs)),
+            [_, date, time] => NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%Y/%m/%d %H:%M:%S")
+                .map(|naive| Some(Utc.from_utc_datetime(&naive).fixed_offset()))
+                .map_err(|_| format!("invalid lease time {} {}", date, time)),
+            _ => Err(format!("invalid lease time {}", words.join(" "))),
+        }
+    }
+
+    /// One or more words ended by `;` or by a `{ ... }` block of simple
+    /// statements, such as `on expiry { ... }`. The block is discarded.
+    fn statement<'a, I>() -> impl Parser<Input = I, Output = Vec<Vec<u8>>> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        let simple = || many1::<Vec<Vec<u8>>, _>(word()).skip(token(b';')).skip(skip_ws());
+        let block = (
+            token(b'{').skip(skip_ws()),
+            skip_many(simple()),
+            token(b'}').skip(skip_ws()),
+        ).map(|_| ());
+        (
+            many1::<Vec<Vec<u8>>, _>(word()),
+            token(b';').skip(skip_ws()).map(|_| ()).or(block),
+        ).map(|(words, _)| words)
+    }
+
+    /// A bare word or a quoted string, followed by any whitespace.
+    fn word<'a, I>() -> impl Parser<Input = I, Output = Vec<u8>> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        let octal = count_min_max::<Vec<u8>, _>(3, 3, satisfy(|c| (b'0'..=b'7').contains(&c)))
+            .map(|digits| digits.iter().fold(0u8, |n, d| n.wrapping_mul(8).wrapping_add(d - b'0')));
+        let escape = token(b'\\').with(attempt(octal).or(satisfy(|_| true)));
+        let quoted = between(token(b'"'), token(b'"'), many::<Vec<u8>, _>(escape.or(satisfy(|c| c != b'"' && c != b'\\'))));
+        let bare = many1::<Vec<u8>, _>(satisfy(|c: u8| !c.is_ascii_whitespace() && !b";{}\"#".contains(&c)));
+        quoted.or(bare).skip(skip_ws())
+    }
+
+    /// Whitespace and `#` comments.
+    fn skip_ws<'a, I>() -> impl Parser<Input = I, Output = ()> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        // Necessary due to rust-lang/rust#24159
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        skip_many(choice((
+            skip_many1(space()),
+            token(b'#').with(skip_many(satisfy(|c| c != b'\n'))),
+        )))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{parse, Lease};
+        use chrono::{TimeZone, Utc};
+        use {MacAddr, ParseStage};
+
+        #[test]
+        fn leases() {
+            let file = br#"# The format of this file is documented in the dhcpd.leases(5) manual page.
+# This lease file was written by isc-dhcp-4.4.1
+
+authoring-byte-order little-endian;
+
+failover peer "dhcp-failover" state {
+  my state normal at 3 2015/06/03 00:00:00;
+  partner state normal at 3 2015/06/03 00:00:00;
+}
+
+lease 192.168.0.77 {
+  starts 3 2015/06/03 00:01:00;
+  ends 3 2015/06/03 12:01:00;
+  cltt 3 2015/06/03 00:01:00;
+  binding state active;
+  next binding state free;
+  rewind binding state free;
+  hardware ethernet 9c:ad:97:d1:65:39;
+  uid "\001\234\255\227\321e9";
+  client-hostname "Joe";
+  on expiry {
+    set ddns-fwd-name = "joe.example.com";
+  }
+}
+lease 192.168.0.78 {
+  starts epoch 1433289660; # Wed Jun 03 00:01:00 2015
+  ends never;
+  binding state free;
+}
+server-duid "\000\001\000\001\034\257\024\221\000\014)\026\245\300";
+"#;
+            assert_eq!(parse(&file[..]), Ok(vec![
+                Lease {
+                    ip_addr: "192.168.0.77".parse().unwrap(),
+                    starts: Some(Utc.with_ymd_and_hms(2015, 6, 3, 0, 1, 0).unwrap().fixed_offset()),
+                    ends: Some(Utc.with_ymd_and_hms(2015, 6, 3, 12, 1, 0).unwrap().fixed_offset()),
+                    binding_state: Some("active".to_string()),
+                    mac_addr: Some(MacAddr([0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39])),
+                    hostname: Some("Joe".to_string()),
+                    client_id: Some(vec![0x01, 0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]),
+                },
+                Lease {
+                    ip_addr: "192.168.0.78".parse().unwrap(),
+                    starts: Some(Utc.with_ymd_and_hms(2015, 6, 3, 0, 1, 0).unwrap().fixed_offset()),
+                    ends: None,
+                    binding_state: Some("free".to_string()),
+                    mac_addr: None,
+                    hostname: None,
+                    client_id: None,
+                },
+            ]));
+            assert_eq!(super::uid(b"01:9c:ad:97:d1:65:39"), vec![0x01, 0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]);
+            assert_eq!(super::uid(b"ab"), b"ab".to_vec());
+        }
+
+        #[test]
+        fn leases_error() {
+            let err = parse(&b"lease 192.168.0.77 {\n  starts 3 2015/06/32 00:01:00;\n}\n"[..]).unwrap_err();
+            assert_eq!(err.stage, ParseStage::Lease);
+            assert_eq!(err.messages, vec!["invalid lease time 2015/06/32 00:01:00".to_string()]);
+
+            let err = parse(&b"lease 192.168.0.77 {\n  starts 3 2015/06/03 00:01:00;\n"[..]).unwrap_err();
+            assert_eq!(err.stage, ParseStage::Lease);
+            assert_eq!(err.found, Some("end of input".to_string()));
+        }
+    }
+}
+
+pub mod http {
+    use combine::{
+        between, eof, many, many1, satisfy, token, Parser, Stream,
+        error::{ParseError, StreamError},
+        stream::StreamErrorFor,
+        parser::byte::{newline, space}};
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::str;
+    use chrono::{DateTime, Duration, FixedOffset};
+    use {ParseStage, SyslogHeader, TimestampOptions};
+
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct LogEntry {
+        pub datetime: DateTime<FixedOffset>,
+        pub header: SyslogHeader,
+        pub attrs: HashMap<String, Vec<u8>>,
+    }
+
+    impl LogEntry {
+        pub fn new(s: &[u8]) -> Result<Self, ::ParseError> {
+            Self::with_options(s, &mut TimestampOptions::default())
+        }
+
+        pub fn with_options(s: &[u8], options: &mut TimestampOptions) -> Result<Self, ::ParseError> {
+            let (datetime, rest) = ::parse_stage(s, s, ParseStage::Datetime, ::timestamp(options.reference))?;
+            let datetime = options.resolve(datetime)?;
+            let (header, rest) = ::parse_stage(s, rest, ParseStage::ProgramTag, ::syslog_header())?;
+            let (attrs, _) = ::parse_stage(s, rest, ParseStage::Attribute, attrs())?;
+            Ok(LogEntry { datetime, header, attrs })
+        }
+    }
+
+    /// A request logged by the Astaro/Sophos `httpproxy`, with its well known
+    /// attributes parsed.
+    ///
+    /// A well known attribute that is empty or doesn't parse is `None`, and
+    /// one that doesn't parse is left in `attrs` as it was logged.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct HttpRequest {
+        pub datetime: DateTime<FixedOffset>,
+        pub header: SyslogHeader,
+        /// `srcip`
+        pub src_ip: Option<IpAddr>,
+        /// `dstip`
+        pub dst_ip: Option<IpAddr>,
+        pub method: Option<String>,
+        pub url: Option<String>,
+        /// `statuscode`
+        pub status: Option<u16>,
+        /// `size`, the bytes sent to the client.
+        pub size: Option<u64>,
+        /// What the proxy did with the request, such as `pass` or `block`.
+        pub action: Option<String>,
+        /// `categoryname`, the web filter's name for the site's category.
+        pub category: Option<String>,
+        /// `ua`
+        pub user_agent: Option<String>,
+        /// `authtime`. The proxy logs durations in microseconds.
+        pub auth_time: Option<Duration>,
+        /// `dnstime`
+        pub dns_time: Option<Duration>,
+        /// `cattime`, spent looking up the category.
+        pub category_time: Option<Duration>,
+        /// `avscantime`
+        pub av_scan_time: Option<Duration>,
+        /// `fullreqtime`, spent on the whole request.
+        pub full_request_time: Option<Duration>,
+        /// The attributes that aren't well known.
+        pub attrs: HashMap<String, Vec<u8>>,
+    }
+
+    impl HttpRequest {
+        pub fn new(s: &[u8]) -> Result<Self, ::ParseError> {
+            LogEntry::new(s).map(HttpRequest::from)
+        }
+
+        pub fn with_options(s: &[u8], options: &mut TimestampOptions) -> Result<Self, ::ParseError> {
+            LogEntry::with_options(s, options).map(HttpRequest::from)
+        }
+    }
+
+    impl From<LogEntry> for HttpRequest {
+        fn from(entry: LogEntry) -> Self {
+            let mut attrs = entry.attrs;
+            let string = |attrs: &mut HashMap<String, Vec<u8>>, key| take(attrs, key).map(|value| String::from_utf8_lossy(&value).into_owned());
+            let micros = |attrs: &mut HashMap<String, Vec<u8>>, key| parse(attrs, key).map(Duration::microseconds);
+            HttpRequest {
+                datetime: entry.datetime,
+                header: entry.header,
+                src_ip: parse(&mut attrs, "srcip"),
+                dst_ip: parse(&mut attrs, "dstip"),
+                method: string(&mut attrs, "method"),
+                url: string(&mut attrs, "url"),
+                status: parse(&mut attrs, "statuscode"),
+                size: parse(&mut attrs, "size"),
+                action: string(&mut attrs, "action"),
+                category: string(&mut attrs, "categoryname"),
+                user_agent: string(&mut attrs, "ua"),
+                auth_time: micros(&mut attrs, "authtime"),
+                dns_time: micros(&mut attrs, "dnstime"),
+                category_time: micros(&mut attrs, "cattime"),
+                av_scan_time: micros(&mut attrs, "avscantime"),
+                full_request_time: micros(&mut attrs, "fullreqtime"),
+                attrs,
+            }
+        }
+    }
+
+    /// Removes `key` from `attrs`, returning its value unless it was empty.
+    fn take(attrs: &mut HashMap<String, Vec<u8>>, key: &str) -> Option<Vec<u8>> {
+        attrs.remove(key).filter(|value| !value.is_empty())
+    }
+
+    /// Removes `key` from `attrs` and returns its value if it parses. A value
+    /// that doesn't is left in `attrs`, unless it was empty.
+    fn parse<T: str::FromStr>(attrs: &mut HashMap<String, Vec<u8>>, key: &str) -> Option<T> {
+        let value = attrs.get(key)?;
+        let parsed = str::from_utf8(value).ok().and_then(|value| value.parse().ok());
+        if parsed.is_some() || value.is_empty() {
+            attrs.remove(key);
+        }
+        parsed
+    }
+
+    fn attr<'a, I>() -> impl Parser<Input = I, Output = (String, Vec<u8>)> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        (
+            many1::<Vec<u8>, _>(satisfy(|c| c != b'=')),
+            token(b'='),
+            between(token(b'"'), token(b'"'), many(satisfy(|c| c != b'"'))),
+        ).and_then(|(k, _, v)| {
+            String::from_utf8(k).map(|k| (k, v)).map_err(StreamErrorFor::<I>::other)
+        })
+    }
+
+    fn attrs<'a, I>() -> impl Parser<Input = I, Output = HashMap<String, Vec<u8>>> + 'a
+    where
+        I: Stream<Item = u8, Range = &'a [u8]> + 'a,
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        let eol = || newline().or(eof().map(|_| b' '));
+        many(attr().skip(space().or(eol())))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{HttpRequest, LogEntry};
+        use {ParseStage, SyslogHeader};
+        use combine::Parser;
+        use std::collections::HashMap;
+        use std::net::Ipv4Addr;
+        use chrono::naive::{NaiveDate, NaiveDateTime, NaiveTime};
+        use chrono::{Duration, TimeZone, Utc};
+        #[test]
+        fn attr() {
+            assert_eq!(
+                super::attr().parse(&b"foo=\"bar\""[..]),
+                Ok((("foo".to_string(), b"bar"[..].to_vec()), &b""[..]))
+            );
+        }
+
+        #[test]
+        fn attrs() {
+            let want: HashMap<String, Vec<u8>> = [
+                ("foo".to_string(), b"bar"[..].to_vec()),
+                ("bat".to_string(), b"baz"[..].to_vec()),
+            ].iter()
+                .cloned()
+                .collect();
+            assert_eq!(
+                super::attrs().parse(&b"foo=\"bar\" bat=\"baz\""[..]),
+                Ok((want, &b""[..]))
+            );
+        }
+
+        #[test]
+        fn date() {
             assert_eq!(
                 ::date().parse(&b"2016:04:03"[..]),
-                Ok((NaiveDate::from_ymd(2016, 4, 3), &b""[..]))
+                Ok((NaiveDate::from_ymd_opt(2016, 4, 3).unwrap(), &b""[..]))
             );
         }
 
@@ -392,15 +2146,15 @@ pub mod http {
         fn time() {
             assert_eq!(
                 ::time().parse(&b"23:59:59"[..]),
-                Ok((NaiveTime::from_hms(23, 59, 59), &b""[..]))
+                Ok((NaiveTime::from_hms_opt(23, 59, 59).unwrap(), &b""[..]))
             );
         }
 
         #[test]
         fn datetime() {
             let want = NaiveDateTime::new(
-                NaiveDate::from_ymd(2016, 4, 3),
-                NaiveTime::from_hms(23, 59, 59),
+                NaiveDate::from_ymd_opt(2016, 4, 3).unwrap(),
+                NaiveTime::from_hms_opt(23, 59, 59).unwrap(),
             );
             assert_eq!(
                 ::datetime().parse(&b"2016:04:03-23:59:59"[..]),
@@ -412,10 +2166,8 @@ pub mod http {
         fn log_entry() {
             let log = &br#"2016:04:03-23:59:59 publicwifi httpproxy[18500]: foo="bar" bat="baz""#[..];
             let want = LogEntry {
-                datetime: NaiveDateTime::new(
-                    NaiveDate::from_ymd(2016, 4, 3),
-                    NaiveTime::from_hms(23, 59, 59),
-                ),
+                datetime: Utc.with_ymd_and_hms(2016, 4, 3, 23, 59, 59).unwrap().fixed_offset(),
+                header: SyslogHeader { host: "publicwifi".to_string(), program: "httpproxy".to_string(), pid: Some(18500) },
                 attrs: [
                     ("foo".to_string(), b"bar"[..].to_vec()),
                     ("bat".to_string(), b"baz"[..].to_vec()),
@@ -423,10 +2175,1973 @@ pub mod http {
                     .cloned()
                     .collect(),
             };
-            assert_eq!(super::log_entry().parse(log), Ok((want.clone(), &b""[..])));
+            assert_eq!(LogEntry::new(log), Ok(want.clone()));
             let logn = &b"2016:04:03-23:59:59 publicwifi httpproxy[18500]: foo=\"bar\" bat=\"baz\"\n"[..];
-            assert_eq!(super::log_entry().parse(logn), Ok((want.clone(), &b""[..])));
+            assert_eq!(LogEntry::new(logn), Ok(want));
+        }
+
+        #[test]
+        fn http_request() {
+            let log = &br#"2016:04:03-23:59:59 publicwifi httpproxy[18500]: id="0001" severity="info" sys="SecureWeb" sub="http" name="http access" action="pass" method="GET" srcip="10.0.0.5" dstip="93.184.216.34" user="" statuscode="200" cached="0" size="1256" url="http://example.com/" referer="" error="" authtime="0" dnstime="1372" cattime="154" avscantime="" fullreqtime="86712" ua="Mozilla/5.0 (iPhone)" category="105" categoryname="Business""#[..];
+            let request = HttpRequest::new(log).unwrap();
+            assert_eq!(request.datetime, Utc.with_ymd_and_hms(2016, 4, 3, 23, 59, 59).unwrap().fixed_offset());
+            assert_eq!(request.src_ip, Some(Ipv4Addr::new(10, 0, 0, 5).into()));
+            assert_eq!(request.dst_ip, Some(Ipv4Addr::new(93, 184, 216, 34).into()));
+            assert_eq!(request.method.as_deref(), Some("GET"));
+            assert_eq!(request.url.as_deref(), Some("http://example.com/"));
+            assert_eq!(request.status, Some(200));
+            assert_eq!(request.size, Some(1256));
+            assert_eq!(request.action.as_deref(), Some("pass"));
+            assert_eq!(request.category.as_deref(), Some("Business"));
+            assert_eq!(request.user_agent.as_deref(), Some("Mozilla/5.0 (iPhone)"));
+            assert_eq!(request.auth_time, Some(Duration::zero()));
+            assert_eq!(request.dns_time, Some(Duration::microseconds(1372)));
+            assert_eq!(request.category_time, Some(Duration::microseconds(154)));
+            assert_eq!(request.av_scan_time, None);
+            assert_eq!(request.full_request_time, Some(Duration::microseconds(86712)));
+            let mut unknown: Vec<&str> = request.attrs.keys().map(String::as_str).collect();
+            unknown.sort();
+            assert_eq!(unknown, vec!["cached", "category", "error", "id", "name", "referer", "severity", "sub", "sys", "user"]);
+
+            // Well known attributes that are empty are dropped, and ones that
+            // are invalid are kept as they were.
+            let request = HttpRequest::new(&br#"2016:04:03-23:59:59 publicwifi httpproxy[18500]: srcip="" statuscode="abc""#[..]).unwrap();
+            assert_eq!((request.src_ip, request.status), (None, None));
+            assert_eq!(request.attrs.len(), 1);
+            assert_eq!(request.attrs["statuscode"], b"abc");
+        }
+
+        #[test]
+        fn log_entry_error() {
+            let err = LogEntry::new(&br#"2016:04:03-23:59:59 publicwifi httpproxy[18500]: foo="bar" bat="baz"#[..]).unwrap_err();
+            assert_eq!(err.stage, ParseStage::Attribute);
+            assert_eq!(err.offset, 67);
+            assert_eq!(err.found, Some("end of input".to_string()));
+            assert_eq!(err.expected, vec!["'\"'".to_string()]);
+
+            let err = LogEntry::new(&b"2016:04:03-23:59:59 publicwifi httpproxy[18500]: foo=\"bar\" b\xffd=\"baz\""[..]).unwrap_err();
+            assert_eq!(err.stage, ParseStage::Attribute);
+            assert_eq!(err.offset, 59);
+        }
+    }
+
+}
+
+/// Works out which MAC address held an IP address at a given time.
+///
+/// Feed an `IpToMacBuilder` with DHCP log entries and lease files in any
+/// order, then `finalize` it into an `IpToMacLookup` to query. Both can be
+/// serialized to save the state between runs.
+pub mod correlate {
+    use chrono::{DateTime, Duration, FixedOffset};
+    use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+    use std::net::IpAddr;
+    use {dhcp, dhcp6, leases, MacAddr};
+
+    /// A MAC address holding an IP address from `start` until `end`, or
+    /// indefinitely if the end isn't known.
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+    pub struct Binding {
+        pub start: DateTime<FixedOffset>,
+        pub mac_addr: MacAddr,
+        pub end: Option<DateTime<FixedOffset>>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct IpToMacBuilder {
+        bindings: HashMap<IpAddr, Vec<Binding>>,
+        /// Releases, declines and expiries, which end a binding early.
+        releases: HashMap<IpAddr, Vec<(DateTime<FixedOffset>, MacAddr)>>,
+        default_lease_secs: i64,
+        /// Addresses whose bindings or releases changed since the last
+        /// `lookup` or `update`.
+        #[serde(skip)]
+        changed: HashSet<IpAddr>,
+    }
+
+    // `changed` only records what `update` has left to do, so it doesn't
+    // take part in comparisons.
+    impl PartialEq for IpToMacBuilder {
+        fn eq(&self, other: &Self) -> bool {
+            self.bindings == other.bindings && self.releases == other.releases && self.default_lease_secs == other.default_lease_secs
+        }
+    }
+
+    impl IpToMacBuilder {
+        /// `default_lease_time` is how long an acknowledgement that doesn't
+        /// log a lease time binds its address for.
+        pub fn new(default_lease_time: Duration) -> Self {
+            IpToMacBuilder { bindings: HashMap::new(), releases: HashMap::new(), default_lease_secs: default_lease_time.num_seconds(), changed: HashSet::new() }
+        }
+
+        /// How long an acknowledgement that doesn't log a lease time binds
+        /// its address for.
+        pub fn default_lease_time(&self) -> Duration {
+            Duration::seconds(self.default_lease_secs)
+        }
+
+        pub fn add_dhcp_ack<A: Into<IpAddr>>(&mut self, date: DateTime<FixedOffset>, ip_addr: A, mac_addr: MacAddr, lease_time: Option<Duration>) {
+            let end = date + lease_time.unwrap_or_else(|| Duration::seconds(self.default_lease_secs));
+            self.add_binding(ip_addr.into(), Binding { start: date, mac_addr, end: Some(end) });
+        }
+
+        /// Ends the binding of `ip_addr` to `mac_addr` at `date`, if it hasn't
+        /// ended already.
+        pub fn add_dhcp_release<A: Into<IpAddr>>(&mut self, date: DateTime<FixedOffset>, ip_addr: A, mac_addr: MacAddr) {
+            let ip_addr = ip_addr.into();
+            let releases = self.releases.entry(ip_addr).or_default();
+            if !releases.contains(&(date, mac_addr)) {
+                releases.push((date, mac_addr));
+                self.changed.insert(ip_addr);
+            }
+        }
+
+        pub fn add_lease<A: Into<IpAddr>>(&mut self, start: DateTime<FixedOffset>, end: Option<DateTime<FixedOffset>>, ip_addr: A, mac_addr: MacAddr) {
+            self.add_binding(ip_addr.into(), Binding { start, mac_addr, end });
+        }
+
+        /// Adds the acknowledgement, release, decline or expiry in `entry`,
+        /// and ignores any other message.
+        pub fn add_dhcp_log_entry(&mut self, entry: &dhcp::LogEntry) {
+            match entry.msg {
+                dhcp::DhcpMsg::Ack{ip_addr, mac_addr, lease_time, ..} => {
+                    self.add_dhcp_ack(entry.datetime, ip_addr, mac_addr, lease_time.map(|secs| Duration::seconds(secs.into())));
+                },
+                dhcp::DhcpMsg::Release{ip_addr, mac_addr, ..} |
+                dhcp::DhcpMsg::Decline{ip_addr, mac_addr, ..} |
+                dhcp::DhcpMsg::Expire{ip_addr, mac_addr, ..} => {
+                    self.add_dhcp_release(entry.datetime, ip_addr, mac_addr);
+                },
+                _ => {},
+            }
+        }
+
+        /// Adds the address assigned by a Reply in `entry`, if the client's
+        /// DUID includes its MAC address.
+        pub fn add_dhcp6_log_entry(&mut self, entry: &dhcp6::LogEntry) {
+            if let dhcp6::Dhcp6Msg{ msg_type: dhcp6::MsgType::Reply, ip_addr: Some(ip_addr), duid: Some(ref duid), lease_time, .. } = entry.msg {
+                if let Some(mac_addr) = duid.mac_addr() {
+                    self.add_dhcp_ack(entry.datetime, ip_addr, mac_addr, lease_time.map(|secs| Duration::seconds(secs.into())));
+                }
+            }
+        }
+
+        /// Adds the leases parsed from a `dhcpd.leases` file. dhcpd appends a
+        /// lease again whenever it changes, so only the last declaration for
+        /// each address, start time and MAC address is used, and it replaces
+        /// any binding added for them before.
+        pub fn add_leases<L: IntoIterator<Item = leases::Lease>>(&mut self, leases: L) {
+            let mut current = HashMap::new();
+            for lease in leases {
+                if let (Some(starts), Some(mac_addr)) = (lease.starts, lease.mac_addr) {
+                    current.insert((lease.ip_addr, starts, mac_addr), lease.ends);
+                }
+            }
+            for ((ip_addr, start, mac_addr), end) in current {
+                let binding = Binding { start, mac_addr, end };
+                let bindings = self.bindings.entry(ip_addr.into()).or_default();
+                match bindings.iter_mut().find(|b| b.start == start && b.mac_addr == mac_addr) {
+                    Some(existing) if *existing == binding => continue,
+                    Some(existing) => *existing = binding,
+                    None => bindings.push(binding),
+                }
+                self.changed.insert(ip_addr.into());
+            }
+        }
+
+        // Bindings that were already added are skipped, so that reading a
+        // log or lease file again doesn't grow a saved builder.
+        fn add_binding(&mut self, ip_addr: IpAddr, binding: Binding) {
+            let bindings = self.bindings.entry(ip_addr).or_default();
+            if !bindings.contains(&binding) {
+                bindings.push(binding);
+                self.changed.insert(ip_addr);
+            }
+        }
+
+        pub fn finalize(self) -> IpToMacLookup {
+            let releases = self.releases;
+            let finalized = self.bindings.into_iter().map(|(k, v)| {
+                let merged = finalize_bindings(v, releases.get(&k).map(Vec::as_slice).unwrap_or(&[]));
+                (k, merged)
+            }).collect();
+            IpToMacLookup(finalized)
+        }
+
+        /// Finalizes the bindings into a lookup that `update` can then keep
+        /// current, without consuming the builder.
+        pub fn lookup(&mut self) -> IpToMacLookup {
+            self.changed.clear();
+            IpToMacLookup(self.bindings.iter().map(|(&ip_addr, bindings)| {
+                (ip_addr, finalize_bindings(bindings.clone(), self.releases(ip_addr)))
+            }).collect())
+        }
+
+        /// Finalizes again only the addresses whose bindings or releases
+        /// changed since `lookup` or the last `update`, returning whether
+        /// there were any.
+        pub fn update(&mut self, lookup: &mut IpToMacLookup) -> bool {
+            let changed = ::std::mem::take(&mut self.changed);
+            for ip_addr in &changed {
+                if let Some(bindings) = self.bindings.get(ip_addr) {
+                    lookup.0.insert(*ip_addr, finalize_bindings(bindings.clone(), self.releases(*ip_addr)));
+                }
+            }
+            !changed.is_empty()
+        }
+
+        fn releases(&self, ip_addr: IpAddr) -> &[(DateTime<FixedOffset>, MacAddr)] {
+            self.releases.get(&ip_addr).map(Vec::as_slice).unwrap_or(&[])
         }
     }
 
+    // Sort the bindings of an ip address by start, and let each release end
+    // the run of bindings by the same mac address that precedes it. Then merge
+    // consecutive overlapping bindings that have the same mac address, ending
+    // the merged binding at the later of their ends.
+    fn finalize_bindings(mut v: Vec<Binding>, releases: &[(DateTime<FixedOffset>, MacAddr)]) -> Vec<Binding> {
+        v.sort_unstable();
+        for &(date, mac_addr) in releases {
+            let released = v.iter().take_while(|binding| binding.start <= date).count();
+            if v[..released].last().is_some_and(|binding| binding.mac_addr == mac_addr) {
+                for binding in v[..released].iter_mut().rev().take_while(|binding| binding.mac_addr == mac_addr) {
+                    if binding.end.is_none_or(|end| end > date) {
+                        binding.end = Some(date);
+                    }
+                }
+            }
+        }
+        let mut merged: Vec<Binding> = Vec::with_capacity(v.len());
+        for binding in v {
+            match merged.last_mut() {
+                Some(last) if last.mac_addr == binding.mac_addr && last.end.is_none_or(|end| end >= binding.start) => {
+                    last.end = match (last.end, binding.end) {
+                        (Some(a), Some(b)) => Some(a.max(b)),
+                        _ => None,
+                    };
+                },
+                _ => merged.push(binding),
+            }
+        }
+        merged
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct IpToMacLookup(HashMap<IpAddr, Vec<Binding>>);
+
+    impl IpToMacLookup {
+        /// The MAC address bound to `ip_addr` at `date`, or `None` if the
+        /// address wasn't leased then.
+        pub fn get_mac(&self, date: DateTime<FixedOffset>, ip_addr: IpAddr) -> Option<MacAddr> {
+            let v: &[Binding] = self.0.get(&ip_addr)?;
+            let binding = v.iter().take_while(|binding| binding.start < date).last()?;
+            match binding.end {
+                Some(end) if end <= date => None,
+                _ => Some(binding.mac_addr),
+            }
+        }
+
+        /// The bindings of `ip_addr`, in order of their start.
+        pub fn bindings(&self, ip_addr: IpAddr) -> &[Binding] {
+            self.0.get(&ip_addr).map(Vec::as_slice).unwrap_or(&[])
+        }
+    }
+
+    /// Collects the hostnames and DHCP client identifiers that MAC addresses
+    /// present, so that the locally administered addresses a client makes up
+    /// for privacy can be linked to one stable identity.
+    ///
+    /// Any hostname or client identifier presented by a locally administered
+    /// address links every address that presented it, so two devices with a
+    /// generic hostname such as `iPhone` may be linked together.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct DeviceLinker {
+        /// By lowercased hostname.
+        by_hostname: BTreeMap<String, BTreeSet<MacAddr>>,
+        /// By client identifier, as lowercase hex.
+        by_client_id: BTreeMap<String, BTreeSet<MacAddr>>,
+    }
+
+    impl DeviceLinker {
+        pub fn new() -> Self {
+            DeviceLinker::default()
+        }
+
+        pub fn add_hostname(&mut self, mac_addr: MacAddr, hostname: &str) {
+            let hostname = hostname.trim().to_lowercase();
+            if !hostname.is_empty() {
+                self.by_hostname.entry(hostname).or_default().insert(mac_addr);
+            }
+        }
+
+        pub fn add_client_id(&mut self, mac_addr: MacAddr, client_id: &[u8]) {
+            if !client_id.is_empty() {
+                let client_id = client_id.iter().map(|byte| format!("{:02x}", byte)).collect();
+                self.by_client_id.entry(client_id).or_default().insert(mac_addr);
+            }
+        }
+
+        /// Adds the hostname and client identifier of any message that has
+        /// them.
+        pub fn add_dhcp_log_entry(&mut self, entry: &dhcp::LogEntry) {
+            use dhcp::DhcpMsg::*;
+            match entry.msg {
+                Ack{mac_addr, ref friendly_name, ref client_id, ..} => {
+                    if let Some(ref friendly_name) = *friendly_name {
+                        self.add_hostname(mac_addr, friendly_name);
+                    }
+                    if let Some(ref client_id) = *client_id {
+                        self.add_client_id(mac_addr, client_id);
+                    }
+                },
+                Offer{mac_addr, friendly_name: Some(ref friendly_name), ..} |
+                Request{mac_addr, friendly_name: Some(ref friendly_name), ..} |
+                Discover{mac_addr, friendly_name: Some(ref friendly_name), ..} => {
+                    self.add_hostname(mac_addr, friendly_name);
+                },
+                _ => {},
+            }
+        }
+
+        pub fn add_leases<'a, L: IntoIterator<Item = &'a leases::Lease>>(&mut self, leases: L) {
+            for lease in leases {
+                if let Some(mac_addr) = lease.mac_addr {
+                    if let Some(ref hostname) = lease.hostname {
+                        self.add_hostname(mac_addr, hostname);
+                    }
+                    if let Some(ref client_id) = lease.client_id {
+                        self.add_client_id(mac_addr, client_id);
+                    }
+                }
+            }
+        }
+
+        /// Links the addresses that share a hostname or client identifier
+        /// with a locally administered address. Each linked group is
+        /// identified by its lowest globally administered address, or by its
+        /// lowest address if it has none.
+        pub fn finalize(&self) -> DeviceIdentities {
+            let mut parent: HashMap<MacAddr, MacAddr> = HashMap::new();
+            fn root(parent: &HashMap<MacAddr, MacAddr>, mut mac_addr: MacAddr) -> MacAddr {
+                while let Some(&next) = parent.get(&mac_addr) {
+                    mac_addr = next;
+                }
+                mac_addr
+            }
+            let groups = self.by_hostname.values().chain(self.by_client_id.values())
+                .filter(|group| group.len() > 1 && group.iter().any(MacAddr::is_locally_administered));
+            for group in groups {
+                let roots: BTreeSet<MacAddr> = group.iter().map(|&mac_addr| root(&parent, mac_addr)).collect();
+                let identity = *roots.iter().min_by_key(|mac_addr| (mac_addr.is_locally_administered(), **mac_addr)).unwrap();
+                for mac_addr in roots {
+                    if mac_addr != identity {
+                        parent.insert(mac_addr, identity);
+                    }
+                }
+            }
+            let identities = parent.keys().map(|&mac_addr| (mac_addr, root(&parent, mac_addr))).collect();
+            DeviceIdentities(identities)
+        }
+    }
+
+    /// The stable identities found by `DeviceLinker`.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct DeviceIdentities(HashMap<MacAddr, MacAddr>);
+
+    impl DeviceIdentities {
+        /// The address identifying the device `mac_addr` belongs to, which
+        /// is `mac_addr` itself unless it was linked to another.
+        pub fn identity(&self, mac_addr: MacAddr) -> MacAddr {
+            self.0.get(&mac_addr).cloned().unwrap_or(mac_addr)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{Binding, DeviceLinker, IpToMacBuilder, IpToMacLookup};
+        use chrono::{DateTime, Duration, FixedOffset, TimeZone, Utc};
+        use std::net::{IpAddr, Ipv4Addr};
+        use serde_json;
+        use {leases, MacAddr};
+
+        const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 77));
+        const MAC1: MacAddr = MacAddr([0x9c, 0xad, 0x97, 0xd1, 0x65, 0x39]);
+        const MAC2: MacAddr = MacAddr([0xa4, 0xdb, 0x30, 0x66, 0x4f, 0x90]);
+
+        fn hour(h: u32) -> DateTime<FixedOffset> {
+            Utc.with_ymd_and_hms(2015, 6, 3, h, 0, 0).unwrap().fixed_offset()
+        }
+
+        fn binding(start: u32, mac_addr: MacAddr, end: u32) -> Binding {
+            Binding { start: hour(start), mac_addr, end: Some(hour(end)) }
+        }
+
+        #[test]
+        fn finalize() {
+            // Renewals by the same client merge into one binding, added in any
+            // order.
+            let mut builder = IpToMacBuilder::new(Duration::hours(4));
+            builder.add_dhcp_ack(hour(2), IP, MAC1, None);
+            builder.add_dhcp_ack(hour(0), IP, MAC1, None);
+            builder.add_dhcp_ack(hour(8), IP, MAC2, None);
+            assert_eq!(builder.finalize().bindings(IP), &[binding(0, MAC1, 6), binding(8, MAC2, 12)]);
+
+            // A gap between leases, or another client in between, keeps
+            // bindings apart.
+            let mut builder = IpToMacBuilder::new(Duration::hours(4));
+            builder.add_dhcp_ack(hour(0), IP, MAC1, None);
+            builder.add_dhcp_ack(hour(5), IP, MAC1, None);
+            builder.add_dhcp_ack(hour(10), IP, MAC2, None);
+            builder.add_dhcp_ack(hour(12), IP, MAC1, Some(Duration::hours(1)));
+            assert_eq!(builder.finalize().bindings(IP), &[binding(0, MAC1, 4), binding(5, MAC1, 9), binding(10, MAC2, 14), binding(12, MAC1, 13)]);
+
+            // A release ends the renewal it follows, and the merged binding.
+            let mut builder = IpToMacBuilder::new(Duration::hours(4));
+            builder.add_dhcp_ack(hour(0), IP, MAC1, None);
+            builder.add_dhcp_ack(hour(2), IP, MAC1, None);
+            builder.add_dhcp_release(hour(3), IP, MAC1);
+            builder.add_dhcp_release(hour(4), IP, MAC2);
+            assert_eq!(builder.finalize().bindings(IP), &[binding(0, MAC1, 3)]);
+
+            // A lease that never ends absorbs later renewals.
+            let mut builder = IpToMacBuilder::new(Duration::hours(4));
+            builder.add_lease(hour(0), None, IP, MAC1);
+            builder.add_dhcp_ack(hour(6), IP, MAC1, None);
+            assert_eq!(builder.finalize().bindings(IP), &[Binding { start: hour(0), mac_addr: MAC1, end: None }]);
+
+            // A shorter renewal doesn't cut a longer lease short.
+            let mut builder = IpToMacBuilder::new(Duration::hours(4));
+            builder.add_dhcp_ack(hour(0), IP, MAC1, Some(Duration::hours(10)));
+            builder.add_dhcp_ack(hour(2), IP, MAC1, Some(Duration::hours(1)));
+            assert_eq!(builder.finalize().bindings(IP), &[binding(0, MAC1, 10)]);
+        }
+
+        #[test]
+        fn get_mac() {
+            let mut builder = IpToMacBuilder::new(Duration::hours(4));
+            builder.add_dhcp_ack(hour(0), IP, MAC1, None);
+            builder.add_dhcp_ack(hour(8), IP, MAC2, None);
+            let lookup = builder.finalize();
+            assert_eq!(lookup.get_mac(hour(0), IP), None);
+            assert_eq!(lookup.get_mac(hour(1), IP), Some(MAC1));
+            assert_eq!(lookup.get_mac(hour(5), IP), None);
+            assert_eq!(lookup.get_mac(hour(9), IP), Some(MAC2));
+            assert_eq!(lookup.get_mac(hour(9), IpAddr::V4(Ipv4Addr::new(192, 168, 0, 78))), None);
+        }
+
+        #[test]
+        fn update() {
+            let mut builder = IpToMacBuilder::new(Duration::hours(4));
+            builder.add_dhcp_ack(hour(0), IP, MAC1, None);
+            let mut lookup = builder.lookup();
+            assert!(!builder.update(&mut lookup));
+
+            // Only the addresses that changed are finalized again, and the
+            // result matches finalizing everything.
+            let other = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 78));
+            builder.add_dhcp_ack(hour(2), IP, MAC1, None);
+            builder.add_dhcp_ack(hour(1), other, MAC2, None);
+            builder.add_dhcp_release(hour(3), other, MAC2);
+            assert!(builder.update(&mut lookup));
+            assert_eq!(lookup, builder.clone().finalize());
+            assert_eq!(lookup.bindings(IP), &[binding(0, MAC1, 6)]);
+            assert_eq!(lookup.bindings(other), &[binding(1, MAC2, 3)]);
+        }
+
+        #[test]
+        fn add_leases() {
+            let lease = |end: u32| leases::Lease {
+                ip_addr: Ipv4Addr::new(192, 168, 0, 77),
+                starts: Some(hour(0)),
+                ends: Some(hour(end)),
+                binding_state: Some("active".to_string()),
+                mac_addr: Some(MAC1),
+                hostname: None,
+                client_id: None,
+            };
+            // Reading a lease file again after dhcpd extended a lease replaces
+            // the binding rather than adding another.
+            let mut builder = IpToMacBuilder::new(Duration::hours(4));
+            builder.add_leases(vec![lease(2), lease(4)]);
+            builder.add_leases(vec![lease(2), lease(4), lease(6)]);
+            assert_eq!(builder.clone().finalize().bindings(IP), &[binding(0, MAC1, 6)]);
+            assert_eq!(builder.bindings[&IP], vec![binding(0, MAC1, 6)]);
+        }
+
+        #[test]
+        fn link() {
+            const PRIVATE1: MacAddr = MacAddr([0x6a, 0x01, 0x02, 0x03, 0x04, 0x05]);
+            const PRIVATE2: MacAddr = MacAddr([0x3e, 0x01, 0x02, 0x03, 0x04, 0x05]);
+            const PRIVATE3: MacAddr = MacAddr([0xb2, 0x01, 0x02, 0x03, 0x04, 0x05]);
+            let mut linker = DeviceLinker::new();
+            // Globally administered addresses sharing a hostname stay apart.
+            linker.add_hostname(MAC1, "laptop");
+            linker.add_hostname(MAC2, "Laptop");
+            let identities = linker.finalize();
+            assert_eq!(identities.identity(MAC1), MAC1);
+            assert_eq!(identities.identity(MAC2), MAC2);
+
+            // A private address joins the device with its hostname, and
+            // another joins it through a client identifier.
+            let mut linker = DeviceLinker::new();
+            linker.add_hostname(MAC1, "phone");
+            linker.add_hostname(PRIVATE1, "Phone");
+            linker.add_client_id(PRIVATE1, &[0xff, 0x01]);
+            linker.add_client_id(PRIVATE2, &[0xff, 0x01]);
+            linker.add_hostname(PRIVATE3, "tablet");
+            let identities = linker.finalize();
+            assert_eq!(identities.identity(PRIVATE1), MAC1);
+            assert_eq!(identities.identity(PRIVATE2), MAC1);
+            assert_eq!(identities.identity(MAC1), MAC1);
+            assert_eq!(identities.identity(PRIVATE3), PRIVATE3);
+
+            // Private addresses alone are identified by the lowest of them.
+            let mut linker = DeviceLinker::new();
+            linker.add_hostname(PRIVATE1, "phone");
+            linker.add_hostname(PRIVATE2, "phone");
+            let json = serde_json::to_string(&linker).unwrap();
+            assert_eq!(serde_json::from_str::<DeviceLinker>(&json).unwrap(), linker);
+            let identities = linker.finalize();
+            assert_eq!(identities.identity(PRIVATE1), PRIVATE2);
+            assert_eq!(identities.identity(PRIVATE2), PRIVATE2);
+        }
+
+        #[test]
+        fn serialize() {
+            let mut builder = IpToMacBuilder::new(Duration::hours(4));
+            builder.add_dhcp_ack(hour(0), IP, MAC1, None);
+            builder.add_dhcp_release(hour(1), IP, MAC1);
+            let json = serde_json::to_string(&builder).unwrap();
+            assert_eq!(serde_json::from_str::<IpToMacBuilder>(&json).unwrap(), builder);
+
+            let mut again = builder.clone();
+            again.add_dhcp_ack(hour(0), IP, MAC1, None);
+            again.add_dhcp_release(hour(1), IP, MAC1);
+            assert_eq!(again, builder);
+
+            let lookup = builder.finalize();
+            let json = serde_json::to_string(&lookup).unwrap();
+            assert_eq!(json, r#"{"192.168.0.77":[{"start":"2015-06-03T00:00:00Z","mac_addr":"9c:ad:97:d1:65:39","end":"2015-06-03T01:00:00Z"}]}"#);
+            assert_eq!(serde_json::from_str::<IpToMacLookup>(&json).unwrap(), lookup);
+        }
+    }
+}
+
+/// Builds an inventory of the devices seen in DHCP logs and lease files:
+/// their hostnames, addresses and when each was seen.
+pub mod inventory {
+    use chrono::{DateTime, FixedOffset};
+    use std::collections::btree_map::{self, BTreeMap};
+    use std::collections::{BTreeSet, HashMap};
+    use std::net::IpAddr;
+    use {dhcp, dhcp6, leases, MacAddr};
+
+    /// When something was first and last seen.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+    pub struct Seen {
+        pub first: DateTime<FixedOffset>,
+        pub last: DateTime<FixedOffset>,
+    }
+
+    impl Seen {
+        pub fn new(at: DateTime<FixedOffset>) -> Self {
+            Seen { first: at, last: at }
+        }
+
+        pub fn add(&mut self, other: Seen) {
+            self.first = self.first.min(other.first);
+            self.last = self.last.max(other.last);
+        }
+    }
+
+    /// What the DHCP history says about one device.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct Device {
+        pub mac_addr: MacAddr,
+        /// Any message to or from the device.
+        pub seen: Seen,
+        pub hostnames: BTreeMap<String, Seen>,
+        /// The addresses the device was given, and when.
+        pub ip_addrs: BTreeMap<IpAddr, Seen>,
+        /// Acknowledgements, renewals included, DHCPv6 replies assigning an
+        /// address, and lease file entries.
+        pub leases: u32,
+    }
+
+    impl Device {
+        pub fn new(mac_addr: MacAddr, at: DateTime<FixedOffset>) -> Self {
+            Device { mac_addr, seen: Seen::new(at), hostnames: BTreeMap::new(), ip_addrs: BTreeMap::new(), leases: 0 }
+        }
+
+        /// Adds what `other` knows about the same device.
+        pub fn merge(&mut self, other: Device) {
+            self.seen.add(other.seen);
+            for (hostname, seen) in other.hostnames {
+                add_seen(&mut self.hostnames, hostname, seen);
+            }
+            for (ip_addr, seen) in other.ip_addrs {
+                add_seen(&mut self.ip_addrs, ip_addr, seen);
+            }
+            self.leases += other.leases;
+        }
+
+        fn add_hostname(&mut self, hostname: Option<&String>, at: DateTime<FixedOffset>) {
+            if let Some(hostname) = hostname {
+                add_seen(&mut self.hostnames, hostname.clone(), Seen::new(at));
+            }
+        }
+
+        fn add_lease(&mut self, ip_addr: IpAddr, at: DateTime<FixedOffset>) {
+            add_seen(&mut self.ip_addrs, ip_addr, Seen::new(at));
+            self.leases += 1;
+        }
+    }
+
+    fn add_seen<K: Ord>(map: &mut BTreeMap<K, Seen>, key: K, seen: Seen) {
+        match map.entry(key) {
+            btree_map::Entry::Occupied(mut occupied) => occupied.get_mut().add(seen),
+            btree_map::Entry::Vacant(vacant) => {
+                vacant.insert(seen);
+            },
+        }
+    }
+
+    /// The devices seen in DHCP logs and lease files, by MAC address.
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    pub struct Inventory {
+        devices: BTreeMap<MacAddr, Device>,
+        /// Devices that log entries or leases added to since `take_changed`.
+        #[serde(skip)]
+        changed: BTreeSet<MacAddr>,
+    }
+
+    // `changed` only records what is left to save, so it doesn't take part in
+    // comparisons.
+    impl PartialEq for Inventory {
+        fn eq(&self, other: &Self) -> bool {
+            self.devices == other.devices
+        }
+    }
+
+    impl Inventory {
+        pub fn new() -> Self {
+            Inventory::default()
+        }
+
+        /// Adds `device`, merging it with what is known about its MAC address.
+        pub fn insert(&mut self, device: Device) {
+            match self.devices.entry(device.mac_addr) {
+                btree_map::Entry::Occupied(mut occupied) => occupied.get_mut().merge(device),
+                btree_map::Entry::Vacant(vacant) => {
+                    vacant.insert(device);
+                },
+            }
+        }
+
+        pub fn add_dhcp_log_entry(&mut self, entry: &dhcp::LogEntry) {
+            use dhcp::DhcpMsg::*;
+            let at = entry.datetime;
+            match entry.msg {
+                Ack{ip_addr, mac_addr, ref friendly_name, ..} => {
+                    let device = self.device(mac_addr, at);
+                    device.add_hostname(friendly_name.as_ref(), at);
+                    device.add_lease(ip_addr.into(), at);
+                },
+                Offer{mac_addr, ref friendly_name, ..} |
+                Request{mac_addr, ref friendly_name, ..} |
+                Discover{mac_addr, ref friendly_name, ..} => {
+                    self.device(mac_addr, at).add_hostname(friendly_name.as_ref(), at);
+                },
+                Nak{mac_addr, ..} |
+                Release{mac_addr, ..} |
+                Decline{mac_addr, ..} |
+                Expire{mac_addr, ..} => {
+                    self.device(mac_addr, at);
+                },
+                Inform{..} => {},
+            }
+        }
+
+        /// Adds a message whose client DUID includes a MAC address.
+        pub fn add_dhcp6_log_entry(&mut self, entry: &dhcp6::LogEntry) {
+            let msg = &entry.msg;
+            let at = entry.datetime;
+            if let Some(mac_addr) = msg.duid.as_ref().and_then(dhcp6::Duid::mac_addr) {
+                let device = self.device(mac_addr, at);
+                device.add_hostname(msg.hostname.as_ref(), at);
+                if let (dhcp6::MsgType::Reply, Some(ip_addr)) = (msg.msg_type, msg.ip_addr) {
+                    device.add_lease(ip_addr.into(), at);
+                }
+            }
+        }
+
+        /// Adds the leases parsed from a `dhcpd.leases` file, using only the
+        /// last declaration for each address, start time and MAC address like
+        /// `IpToMacBuilder::add_leases`.
+        pub fn add_leases<L: IntoIterator<Item = leases::Lease>>(&mut self, leases: L) {
+            let mut current = HashMap::new();
+            for lease in leases {
+                current.insert((lease.ip_addr, lease.starts, lease.mac_addr), lease);
+            }
+            for lease in current.into_values() {
+                if let (Some(starts), Some(mac_addr)) = (lease.starts, lease.mac_addr) {
+                    let device = self.device(mac_addr, starts);
+                    device.add_hostname(lease.hostname.as_ref(), starts);
+                    device.add_lease(lease.ip_addr.into(), starts);
+                }
+            }
+        }
+
+        pub fn get(&self, mac_addr: MacAddr) -> Option<&Device> {
+            self.devices.get(&mac_addr)
+        }
+
+        /// The devices in order of MAC address.
+        pub fn devices(&self) -> btree_map::Values<'_, MacAddr, Device> {
+            self.devices.values()
+        }
+
+        /// The devices that log entries or leases were added to since the
+        /// last call, in order of MAC address. Devices merged in with
+        /// `insert` aren't included.
+        pub fn take_changed(&mut self) -> Vec<&Device> {
+            let changed = ::std::mem::take(&mut self.changed);
+            let devices = &self.devices;
+            changed.iter().filter_map(|mac_addr| devices.get(mac_addr)).collect()
+        }
+
+        /// The device with `mac_addr`, recording that it was seen `at`.
+        fn device(&mut self, mac_addr: MacAddr, at: DateTime<FixedOffset>) -> &mut Device {
+            self.changed.insert(mac_addr);
+            let device = self.devices.entry(mac_addr).or_insert_with(|| Device::new(mac_addr, at));
+            device.seen.add(Seen::new(at));
+            device
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{Device, Inventory, Seen};
+        use {dhcp, dhcp6, MacAddr};
+
+        fn mac() -> MacAddr {
+            "9c:ad:97:d1:65:39".parse().unwrap()
+        }
+
+        #[test]
+        fn inventory() {
+            let mut inventory = Inventory::new();
+            for line in &[
+                &b"2015:06:03-00:01:00 PublicWiFi dhcpd: DHCPDISCOVER from 9c:ad:97:d1:65:39 (Joe) via eth0"[..],
+                b"2015:06:03-00:01:01 PublicWiFi dhcpd: DHCPACK on 192.168.0.77 to 9c:ad:97:d1:65:39 (Joe) via eth0",
+                b"2015:06:03-06:01:01 PublicWiFi dhcpd: DHCPACK on 192.168.0.77 to 9c:ad:97:d1:65:39 (Joes-iPhone) via eth0",
+                b"2015:06:03-07:00:00 PublicWiFi dhcpd: DHCPRELEASE of 192.168.0.77 from 9c:ad:97:d1:65:39 via eth0 (found)",
+                b"2015:06:03-07:00:00 PublicWiFi dhcpd: DHCPINFORM from 192.168.0.78 via eth0",
+            ] {
+                inventory.add_dhcp_log_entry(&dhcp::LogEntry::new(line).unwrap());
+            }
+            inventory.add_dhcp6_log_entry(&dhcp6::LogEntry::new(b"2015:06:03-08:00:00 PublicWiFi dhcpd: Reply NA: address 2001:db8::100 to client with duid 00:01:00:01:1d:2c:3a:4b:9c:ad:97:d1:65:39 iaid = 2843839090 valid for 43200 seconds").unwrap());
+            assert_eq!(inventory.devices().count(), 1);
+            assert_eq!(inventory.take_changed().iter().map(|device| device.mac_addr).collect::<Vec<_>>(), vec![mac()]);
+            assert!(inventory.take_changed().is_empty());
+            let device = inventory.get(mac()).unwrap();
+            assert_eq!(device.seen.first.to_rfc3339(), "2015-06-03T00:01:00+00:00");
+            assert_eq!(device.seen.last.to_rfc3339(), "2015-06-03T08:00:00+00:00");
+            assert_eq!(device.hostnames.keys().collect::<Vec<_>>(), vec!["Joe", "Joes-iPhone"]);
+            assert_eq!(device.hostnames["Joe"].last.to_rfc3339(), "2015-06-03T00:01:01+00:00");
+            assert_eq!(device.ip_addrs.keys().map(|ip| ip.to_string()).collect::<Vec<_>>(), vec!["192.168.0.77", "2001:db8::100"]);
+            assert_eq!(device.leases, 3);
+
+            // Merging in a device saved earlier.
+            let mut earlier = Device::new(mac(), device.seen.first - ::chrono::Duration::days(1));
+            earlier.hostnames.insert("Joe".to_string(), Seen::new(earlier.seen.first));
+            earlier.leases = 2;
+            inventory.insert(earlier);
+            let device = inventory.get(mac()).unwrap();
+            assert_eq!(device.seen.first.to_rfc3339(), "2015-06-02T00:01:00+00:00");
+            assert_eq!(device.hostnames["Joe"].first.to_rfc3339(), "2015-06-02T00:01:00+00:00");
+            assert_eq!(device.leases, 5);
+            assert!(inventory.take_changed().is_empty());
+        }
+    }
+}
+
+pub mod decompress {
+    use bzip2::bufread::MultiBzDecoder;
+    use flate2::bufread::MultiGzDecoder;
+    use std::fs::File;
+    use std::io::{self, BufRead, BufReader};
+    use std::path::Path;
+    use xz2::bufread::XzDecoder;
+    use zstd::stream::read::Decoder as ZstdDecoder;
+
+    /// How a file is compressed, going by its magic number.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Format {
+        Plain,
+        Gzip,
+        Bzip2,
+        Xz,
+        Zstd,
+    }
+
+    impl Format {
+        pub fn detect(magic: &[u8]) -> Format {
+            if magic.starts_with(b"\x1f\x8b") {
+                Format::Gzip
+            } else if magic.starts_with(b"BZh") {
+                Format::Bzip2
+            } else if magic.starts_with(b"\xfd7zXZ\x00") {
+                Format::Xz
+            } else if magic.starts_with(b"\x28\xb5\x2f\xfd") {
+                Format::Zstd
+            } else {
+                Format::Plain
+            }
+        }
+    }
+
+    /// Wraps `reader` to decompress it as it is read, if it starts with a
+    /// known magic number.
+    pub fn reader<'a, R: BufRead + 'a>(mut reader: R) -> io::Result<(Format, Box<dyn BufRead + 'a>)> {
+        let format = Format::detect(reader.fill_buf()?);
+        let reader: Box<dyn BufRead + 'a> = match format {
+            Format::Plain => Box::new(reader),
+            Format::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+            Format::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(reader))),
+            Format::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+            Format::Zstd => Box::new(BufReader::new(ZstdDecoder::with_buffer(reader)?)),
+        };
+        Ok((format, reader))
+    }
+
+    /// Opens `path`, decompressing it if it is compressed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn BufRead>> {
+        Ok(reader(BufReader::new(File::open(path)?))?.1)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{reader, Format};
+        use bzip2::write::BzEncoder;
+        use flate2::write::GzEncoder;
+        use std::io::{Read, Write};
+        use xz2::write::XzEncoder;
+
+        fn compress<W: Write>(mut encoder: W, data: &[u8]) -> W {
+            encoder.write_all(data).unwrap();
+            encoder
+        }
+
+        #[test]
+        fn decompress() {
+            let data = b"one\ntwo\n";
+            let cases = vec![
+                (Format::Plain, data.to_vec()),
+                (Format::Gzip, compress(GzEncoder::new(Vec::new(), Default::default()), data).finish().unwrap()),
+                (Format::Bzip2, compress(BzEncoder::new(Vec::new(), Default::default()), data).finish().unwrap()),
+                (Format::Xz, compress(XzEncoder::new(Vec::new(), 6), data).finish().unwrap()),
+                (Format::Zstd, ::zstd::encode_all(&data[..], 0).unwrap()),
+            ];
+            for (format, compressed) in cases {
+                let (detected, mut decompressed) = reader(&compressed[..]).unwrap();
+                assert_eq!(detected, format);
+                let mut contents = Vec::new();
+                decompressed.read_to_end(&mut contents).unwrap();
+                assert_eq!(contents, data);
+            }
+        }
+
+        #[test]
+        fn concatenated() {
+            // gzip -c a >> b, as some rotation setups do.
+            let mut compressed = compress(GzEncoder::new(Vec::new(), Default::default()), b"one\n").finish().unwrap();
+            compressed.extend(compress(GzEncoder::new(Vec::new(), Default::default()), b"two\n").finish().unwrap());
+            let mut contents = String::new();
+            reader(&compressed[..]).unwrap().1.read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, "one\ntwo\n");
+        }
+    }
+}
+
+pub mod walk {
+    use globset::{self, Glob, GlobSet, GlobSetBuilder};
+    use std::fmt;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use walkdir::{self, WalkDir};
+
+    #[derive(Debug)]
+    pub enum Error {
+        Walk(walkdir::Error),
+        Glob(globset::Error),
+        Io(io::Error),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                Error::Walk(ref e) => write!(f, "{}", e),
+                Error::Glob(ref e) => write!(f, "{}", e),
+                Error::Io(ref e) => write!(f, "{}", e),
+            }
+        }
+    }
+
+    impl ::std::error::Error for Error {}
+
+    impl From<walkdir::Error> for Error {
+        fn from(e: walkdir::Error) -> Self {
+            Error::Walk(e)
+        }
+    }
+
+    impl From<globset::Error> for Error {
+        fn from(e: globset::Error) -> Self {
+            Error::Glob(e)
+        }
+    }
+
+    impl From<io::Error> for Error {
+        fn from(e: io::Error) -> Self {
+            Error::Io(e)
+        }
+    }
+
+    /// The order files are read in.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SortBy {
+        /// By path, comparing one directory level at a time.
+        Name,
+        /// Oldest modification time first, then by path.
+        Modified,
+    }
+
+    impl FromStr for SortBy {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "name" => Ok(SortBy::Name),
+                "mtime" => Ok(SortBy::Modified),
+                _ => Err(format!("unknown sort order {:?}, expected name or mtime", s)),
+            }
+        }
+    }
+
+    /// Which files under a directory to read, and in what order. Patterns
+    /// are matched against paths relative to the directory walked, and `*`
+    /// matches across `/`.
+    #[derive(Debug, Clone)]
+    pub struct FileFilter {
+        include: Option<GlobSet>,
+        exclude: GlobSet,
+        sort_by: SortBy,
+    }
+
+    impl FileFilter {
+        /// Every file is included when `include` is empty.
+        pub fn new<S: AsRef<str>>(include: &[S], exclude: &[S], sort_by: SortBy) -> Result<Self, Error> {
+            let include = if include.is_empty() { None } else { Some(glob_set(include)?) };
+            Ok(FileFilter { include, exclude: glob_set(exclude)?, sort_by })
+        }
+
+        pub fn matches<P: AsRef<Path>>(&self, relative_path: P) -> bool {
+            let relative_path = relative_path.as_ref();
+            self.include.as_ref().is_none_or(|include| include.is_match(relative_path)) && !self.exclude.is_match(relative_path)
+        }
+
+        /// Lists the matching files under `path`, recursively. A `path` that
+        /// isn't a directory is listed as it is, without filtering.
+        pub fn files<P: AsRef<Path>>(&self, path: P) -> Result<Vec<PathBuf>, Error> {
+            let path = path.as_ref();
+            if !path.is_dir() {
+                return Ok(vec![path.to_path_buf()]);
+            }
+            let mut files = Vec::new();
+            for entry in WalkDir::new(path).follow_links(true).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+                let entry = entry?;
+                let relative_path = entry.path().strip_prefix(path).unwrap_or_else(|_| entry.path());
+                if entry.file_type().is_file() && self.matches(relative_path) {
+                    let modified = match self.sort_by {
+                        SortBy::Name => None,
+                        SortBy::Modified => Some(entry.metadata()?.modified()?),
+                    };
+                    files.push((modified, entry.into_path()));
+                }
+            }
+            // Stable, so files modified at the same time stay in name order.
+            files.sort_by_key(|&(modified, _)| modified);
+            Ok(files.into_iter().map(|(_, path)| path).collect())
+        }
+    }
+
+    fn glob_set<S: AsRef<str>>(patterns: &[S]) -> Result<GlobSet, Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(Glob::new(pattern.as_ref())?);
+        }
+        Ok(builder.build()?)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{FileFilter, SortBy};
+        use std::env;
+        use std::fs;
+        use std::path::PathBuf;
+
+        #[test]
+        fn files() {
+            let dir = env::temp_dir().join(format!("parse-logs-walk-{}", ::std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            for path in &["site/2016/10/dhcpd.log", "site/2016/9/dhcpd.log.1.gz", "site/2016/10/.dhcpd.log.swp", "README", "failures.log"] {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, b"").unwrap();
+            }
+            let relative = |files: Vec<PathBuf>| files.iter().map(|f| f.strip_prefix(&dir).unwrap().to_string_lossy().into_owned()).collect::<Vec<_>>();
+            let filter = FileFilter::new(&["*.log*"], &["*.swp", "failures.log"], SortBy::Name).unwrap();
+            assert_eq!(relative(filter.files(&dir).unwrap()), vec!["site/2016/10/dhcpd.log", "site/2016/9/dhcpd.log.1.gz"]);
+            let filter = FileFilter::new::<&str>(&[], &[], SortBy::Name).unwrap();
+            assert_eq!(filter.files(&dir).unwrap().len(), 5);
+            assert_eq!(filter.files(dir.join("README")).unwrap(), vec![dir.join("README")]);
+            assert!(FileFilter::new(&["a[b"], &[], SortBy::Name).is_err());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+/// Records how far into each log file a database has ingested, so that later
+/// runs only read lines that are new.
+///
+/// A file is recognised by a checksum of its first few kilobytes rather than
+/// by its name, so progress follows a log when it is rotated to a new name,
+/// and a log that is truncated or replaced is read again from the start.
+pub mod ledger {
+    use decompress::{self, Format};
+    use rusqlite::{self, Connection};
+    use std::fmt;
+    use std::fs::{self, File};
+    use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+
+    /// How many bytes at the start of a file are checksummed to identify it.
+    const HEAD_LEN: u64 = 4096;
+
+    #[derive(Debug)]
+    pub enum Error {
+        Io(io::Error),
+        Sql(rusqlite::Error),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                Error::Io(ref e) => write!(f, "{}", e),
+                Error::Sql(ref e) => write!(f, "{}", e),
+            }
+        }
+    }
+
+    impl ::std::error::Error for Error {}
+
+    impl From<io::Error> for Error {
+        fn from(e: io::Error) -> Self {
+            Error::Io(e)
+        }
+    }
+
+    impl From<rusqlite::Error> for Error {
+        fn from(e: rusqlite::Error) -> Self {
+            Error::Sql(e)
+        }
+    }
+
+    /// A row of a ledger table. Each row follows one file's
+    /// contents, so a rotated log keeps its row and the new log at its old
+    /// name gets another.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct FileProgress {
+        /// The name the file was last read under.
+        pub path: PathBuf,
+        pub inode: u64,
+        pub size: u64,
+        /// The offset just past the last complete line ingested.
+        pub offset: u64,
+        /// FNV-1a hash of the file's first `min(offset, 4096)` bytes.
+        pub checksum: u64,
+    }
+
+    /// The files ingested into a table, as they were when loaded. Files are
+    /// matched against this snapshot, so the order files are read in doesn't
+    /// matter.
+    #[derive(Debug)]
+    pub struct Ledger {
+        /// `None` for a ledger kept outside the database.
+        table: Option<String>,
+        files: Vec<(i64, FileProgress)>,
+    }
+
+    impl Ledger {
+        /// The ledger of the files ingested into `log_table`.
+        pub fn table_name(log_table: &str) -> String {
+            format!("{}_ingested_files", log_table)
+        }
+
+        /// Loads the ledger of `log_table` from `conn`, creating its table if
+        /// needed.
+        pub fn load(conn: &Connection, log_table: &str) -> Result<Self, Error> {
+            let table = Ledger::table_name(log_table);
+            conn.execute(&format!("CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, path TEXT, inode INTEGER, size INTEGER, offset INTEGER, checksum TEXT);", table), &[])?;
+            let mut stmt = conn.prepare(&format!("SELECT id, path, inode, size, offset, checksum FROM {}", table))?;
+            let files = stmt.query_map(&[], |row| {
+                let (path, inode, size, offset, checksum): (String, i64, i64, i64, String) = (row.get(1), row.get(2), row.get(3), row.get(4), row.get(5));
+                (row.get(0), FileProgress {
+                    path: PathBuf::from(path),
+                    inode: inode as u64,
+                    size: size as u64,
+                    offset: offset as u64,
+                    checksum: u64::from_str_radix(&checksum, 16).unwrap_or(0),
+                })
+            })?.collect::<Result<Vec<_>, _>>()?;
+            Ok(Ledger { table: Some(table), files })
+        }
+
+        /// A ledger of `files` kept elsewhere, such as in a state file.
+        /// `LogFile::save` does nothing for the files it opens; record their
+        /// `progress` instead.
+        pub fn from_progress<F: IntoIterator<Item = FileProgress>>(files: F) -> Self {
+            Ledger { table: None, files: (0..).zip(files).collect() }
+        }
+
+        /// Forgets every file ingested into `log_table`.
+        pub fn clear(conn: &Connection, log_table: &str) -> Result<(), Error> {
+            conn.execute(&format!("DROP TABLE IF EXISTS {}", Ledger::table_name(log_table)), &[])?;
+            Ok(())
+        }
+
+        /// Opens `path` positioned after the lines that were already
+        /// ingested from it, under this or any earlier name. Offsets count
+        /// decompressed bytes, so a log that was compressed after it was
+        /// partly read carries on where it left off.
+        pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<LogFile, Error> {
+            let mut file = LogFile::open(path)?;
+            // The size of a compressed file says nothing about its contents.
+            let compressed = file.format != Format::Plain;
+            let (id, offset) = self.files.iter()
+                .filter(|(_, progress)| progress.offset > 0 && (compressed || progress.offset <= file.size))
+                .filter(|(_, progress)| checksum(&file.head, progress.offset) == progress.checksum)
+                .map(|&(id, ref progress)| (Some(id), progress.offset))
+                .max_by_key(|&(_, offset)| offset)
+                .unwrap_or((None, 0));
+            file.skip(offset)?;
+            file.ledger = self.table.clone();
+            file.id = id;
+            Ok(file)
+        }
+    }
+
+    /// A log file being read a line at a time from where the ledger left off.
+    pub struct LogFile {
+        /// The ledger table to save progress to, if the file was opened
+        /// through one.
+        ledger: Option<String>,
+        /// The file's row in the ledger, if it has one yet.
+        id: Option<i64>,
+        path: PathBuf,
+        inode: u64,
+        size: u64,
+        /// The first `HEAD_LEN` bytes, after decompression.
+        head: Vec<u8>,
+        format: Format,
+        file: File,
+        reader: Box<dyn BufRead>,
+        /// The start of a line that hasn't been terminated yet.
+        partial: Vec<u8>,
+        offset: u64,
+    }
+
+    impl LogFile {
+        /// Opens `path` to read from the start, without consulting a ledger.
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+            let path = path.as_ref();
+            let mut file = File::open(path)?;
+            let metadata = file.metadata()?;
+            let mut head = Vec::new();
+            let format = {
+                let (format, reader) = decompress::reader(BufReader::new(&file))?;
+                reader.take(HEAD_LEN).read_to_end(&mut head)?;
+                format
+            };
+            file.seek(SeekFrom::Start(0))?;
+            let reader = decompress::reader(BufReader::new(file.try_clone()?))?.1;
+            Ok(LogFile {
+                ledger: None,
+                id: None,
+                path: path.to_path_buf(),
+                inode: inode(&metadata),
+                size: metadata.len(),
+                head,
+                format,
+                file,
+                reader,
+                partial: Vec::new(),
+                offset: 0,
+            })
+        }
+
+        /// Moves on to `offset`, straight after opening. Compressed files
+        /// are read up to it, as they can't be seeked.
+        fn skip(&mut self, offset: u64) -> io::Result<()> {
+            if self.format == Format::Plain {
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.reader = Box::new(BufReader::new(self.file.try_clone()?));
+            } else {
+                io::copy(&mut (&mut self.reader).take(offset), &mut io::sink())?;
+            }
+            self.offset = offset;
+            Ok(())
+        }
+
+        /// Reads the next complete line, without its newline. An unterminated
+        /// line at the end of the file is held back until it is finished.
+        pub fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+            self.reader.read_until(b'\n', &mut self.partial)?;
+            if self.partial.last() != Some(&b'\n') {
+                return Ok(None);
+            }
+            let start = self.offset;
+            self.offset += self.partial.len() as u64;
+            // Keep the checksummed head covering everything read so far, as
+            // the file may have been shorter than it when opened.
+            let head_len = self.head.len() as u64;
+            if head_len < HEAD_LEN && self.offset > head_len {
+                let (from, to) = (head_len - start, HEAD_LEN.min(self.offset) - start);
+                self.head.extend_from_slice(&self.partial[from as usize..to as usize]);
+            }
+            let mut line = ::std::mem::take(&mut self.partial);
+            line.pop();
+            Ok(Some(line))
+        }
+
+        /// Reads and discards the lines up to the end of the file, for
+        /// following only what is written after now.
+        pub fn skip_to_end(&mut self) -> io::Result<()> {
+            while self.read_line()?.is_some() {}
+            Ok(())
+        }
+
+        /// Whether the file at `path` has been replaced or truncated since it
+        /// was opened, as happens when a log is rotated. Read the rest of this
+        /// file before switching to the new one with `reopen`.
+        pub fn rotated(&self) -> io::Result<bool> {
+            match fs::metadata(&self.path) {
+                Ok(metadata) => Ok(inode(&metadata) != self.inode || (self.format == Format::Plain && metadata.len() < self.offset)),
+                // Moved away, and not replaced yet.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+
+        /// Opens the file now at `path` to read from the start, saving to
+        /// the same ledger.
+        pub fn reopen(&self) -> Result<Self, Error> {
+            let mut file = LogFile::open(&self.path)?;
+            file.ledger = self.ledger.clone();
+            Ok(file)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        /// Whether `path` names this file, either as the name it was opened
+        /// under or as a new name it was rotated to while being read.
+        pub fn is_same_file<P: AsRef<Path>>(&self, path: P) -> bool {
+            let path = path.as_ref();
+            path == self.path || (self.inode != 0 && fs::metadata(path).map(|metadata| inode(&metadata) == self.inode).unwrap_or(false))
+        }
+
+        /// When the file was last written to, which bounds the timestamps
+        /// logged in it.
+        pub fn modified(&self) -> io::Result<SystemTime> {
+            self.file.metadata()?.modified()
+        }
+
+        /// The offset just past the last line returned by `read_line`.
+        pub fn offset(&self) -> u64 {
+            self.offset
+        }
+
+        pub fn progress(&self) -> FileProgress {
+            FileProgress {
+                path: self.path.clone(),
+                inode: self.inode,
+                size: self.size.max(self.offset),
+                offset: self.offset,
+                checksum: checksum(&self.head, self.offset),
+            }
+        }
+
+        /// Records in `conn` that the file has been ingested up to `offset`.
+        /// Call this in the same transaction as the inserts it covers. Files
+        /// not opened through a ledger aren't recorded.
+        pub fn save(&mut self, conn: &Connection) -> Result<(), Error> {
+            let table = match self.ledger {
+                Some(ref table) => table,
+                None => return Ok(()),
+            };
+            if self.id.is_none() && self.offset == 0 {
+                return Ok(());
+            }
+            let progress = self.progress();
+            let (path, inode, size, offset, checksum) = (
+                progress.path.to_string_lossy(),
+                progress.inode as i64,
+                progress.size as i64,
+                progress.offset as i64,
+                format!("{:016x}", progress.checksum));
+            match self.id {
+                Some(id) => {
+                    conn.execute(
+                        &format!("UPDATE {} SET path = ?, inode = ?, size = ?, offset = ?, checksum = ? WHERE id = ?", table),
+                        &[&path, &inode, &size, &offset, &checksum, &id])?;
+                },
+                None => {
+                    conn.execute(
+                        &format!("INSERT INTO {} (path, inode, size, offset, checksum) VALUES (?, ?, ?, ?, ?)", table),
+                        &[&path, &inode, &size, &offset, &checksum])?;
+                    self.id = Some(conn.last_insert_rowid());
+                },
+            }
+            Ok(())
+        }
+    }
+
+    impl fmt::Debug for LogFile {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("LogFile")
+                .field("id", &self.id)
+                .field("path", &self.path)
+                .field("format", &self.format)
+                .field("offset", &self.offset)
+                .finish()
+        }
+    }
+
+    /// FNV-1a hash of the first `min(len, HEAD_LEN)` bytes of `head`.
+    fn checksum(head: &[u8], len: u64) -> u64 {
+        let len = len.min(HEAD_LEN).min(head.len() as u64) as usize;
+        head[..len].iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3))
+    }
+
+    #[cfg(unix)]
+    fn inode(metadata: &::std::fs::Metadata) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        metadata.ino()
+    }
+
+    #[cfg(not(unix))]
+    fn inode(_metadata: &::std::fs::Metadata) -> u64 {
+        0
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::Ledger;
+        use flate2::write::GzEncoder;
+        use rusqlite::Connection;
+        use std::env;
+        use std::fs::{self, OpenOptions};
+        use std::io::Write;
+        use std::path::PathBuf;
+
+        fn temp_dir(name: &str) -> PathBuf {
+            let dir = env::temp_dir().join(format!("parse-logs-{}-{}", name, ::std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            dir
+        }
+
+        fn append(path: &PathBuf, data: &[u8]) {
+            OpenOptions::new().create(true).append(true).open(path).unwrap().write_all(data).unwrap();
+        }
+
+        fn read_lines(conn: &Connection, path: &PathBuf) -> Vec<String> {
+            let mut file = Ledger::load(conn, "logs").unwrap().open(path).unwrap();
+            let mut lines = Vec::new();
+            while let Some(line) = file.read_line().unwrap() {
+                lines.push(String::from_utf8(line).unwrap());
+            }
+            file.save(conn).unwrap();
+            lines
+        }
+
+        #[test]
+        fn resume() {
+            let dir = temp_dir("resume");
+            let conn = Connection::open_in_memory().unwrap();
+            let log = dir.join("dhcp.log");
+            append(&log, b"one\ntwo\nthr");
+            assert_eq!(read_lines(&conn, &log), vec!["one", "two"]);
+            append(&log, b"ee\n");
+            assert_eq!(read_lines(&conn, &log), vec!["three"]);
+            assert_eq!(read_lines(&conn, &log), Vec::<String>::new());
+
+            // Rotation keeps the progress under the new name, and the new log
+            // is read from the start.
+            fs::rename(&log, dir.join("dhcp.log.1")).unwrap();
+            append(&log, b"four\n");
+            append(&dir.join("dhcp.log.1"), b"late\n");
+            assert_eq!(read_lines(&conn, &log), vec!["four"]);
+            assert_eq!(read_lines(&conn, &dir.join("dhcp.log.1")), vec!["late"]);
+
+            // So is a log that was truncated.
+            fs::write(&log, b"five\n").unwrap();
+            assert_eq!(read_lines(&conn, &log), vec!["five"]);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn follow() {
+            let dir = temp_dir("follow");
+            let conn = Connection::open_in_memory().unwrap();
+            let log = dir.join("dhcp.log");
+            append(&log, b"one\n");
+            let mut file = Ledger::load(&conn, "logs").unwrap().open(&log).unwrap();
+            assert_eq!(file.read_line().unwrap(), Some(b"one".to_vec()));
+            assert_eq!(file.read_line().unwrap(), None);
+            append(&log, b"two\n");
+            assert_eq!(file.read_line().unwrap(), Some(b"two".to_vec()));
+            assert!(!file.rotated().unwrap());
+            file.save(&conn).unwrap();
+            append(&log, b"three\n");
+            assert_eq!(read_lines(&conn, &log), vec!["three"]);
+
+            fs::rename(&log, dir.join("dhcp.log.1")).unwrap();
+            assert!(!file.rotated().unwrap());
+            assert!(file.is_same_file(dir.join("dhcp.log.1")));
+            append(&log, b"four\n");
+            assert!(!file.is_same_file(dir.join("dhcp.log.2")));
+            assert!(file.rotated().unwrap());
+            let mut file = file.reopen().unwrap();
+            assert_eq!(file.read_line().unwrap(), Some(b"four".to_vec()));
+
+            fs::write(&log, b"").unwrap();
+            assert!(file.rotated().unwrap());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn from_progress() {
+            let dir = temp_dir("from-progress");
+            let log = dir.join("dhcp.log");
+            append(&log, b"one\n");
+            let mut file = Ledger::from_progress(Vec::new()).open(&log).unwrap();
+            assert_eq!(file.read_line().unwrap(), Some(b"one".to_vec()));
+            let progress = file.progress();
+            append(&log, b"two\n");
+            let mut file = Ledger::from_progress(vec![progress]).open(&log).unwrap();
+            assert_eq!(file.read_line().unwrap(), Some(b"two".to_vec()));
+            assert_eq!(file.read_line().unwrap(), None);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn compressed() {
+            let dir = temp_dir("compressed");
+            let conn = Connection::open_in_memory().unwrap();
+            let log = dir.join("dhcp.log");
+            append(&log, b"one\ntwo\n");
+            assert_eq!(read_lines(&conn, &log), vec!["one", "two"]);
+            append(&log, b"three\n");
+
+            // Rotated and compressed before the rest was read.
+            let mut encoder = GzEncoder::new(Vec::new(), Default::default());
+            encoder.write_all(&fs::read(&log).unwrap()).unwrap();
+            let gz = dir.join("dhcp.log.1.gz");
+            fs::write(&gz, encoder.finish().unwrap()).unwrap();
+            fs::remove_file(&log).unwrap();
+            assert_eq!(read_lines(&conn, &gz), vec!["three"]);
+            assert_eq!(read_lines(&conn, &gz), Vec::<String>::new());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+pub mod output {
+    use ledger::{self, Ledger};
+    use rusqlite::{self, Connection};
+    use serde_json;
+    use std::fmt;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, BufReader};
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+
+    #[derive(Debug)]
+    pub enum Error {
+        Io(io::Error),
+        Json(serde_json::Error),
+        Sql(rusqlite::Error),
+        Ledger(ledger::Error),
+        InvalidPrefix(String),
+        TableExists(String),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                Error::Io(ref e) => write!(f, "{}", e),
+                Error::Json(ref e) => write!(f, "{}", e),
+                Error::Sql(ref e) => write!(f, "{}", e),
+                Error::Ledger(ref e) => write!(f, "{}", e),
+                Error::InvalidPrefix(ref prefix) => write!(f, "table prefix {:?} must start with a letter or underscore and contain only letters, digits and underscores", prefix),
+                Error::TableExists(ref table) => write!(f, "table {} already exists", table),
+            }
+        }
+    }
+
+    impl ::std::error::Error for Error {}
+
+    impl From<io::Error> for Error {
+        fn from(e: io::Error) -> Self {
+            Error::Io(e)
+        }
+    }
+
+    impl From<serde_json::Error> for Error {
+        fn from(e: serde_json::Error) -> Self {
+            Error::Json(e)
+        }
+    }
+
+    impl From<rusqlite::Error> for Error {
+        fn from(e: rusqlite::Error) -> Self {
+            Error::Sql(e)
+        }
+    }
+
+    impl From<ledger::Error> for Error {
+        fn from(e: ledger::Error) -> Self {
+            Error::Ledger(e)
+        }
+    }
+
+    /// What to do with a table that already holds rows.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Mode {
+        /// Refuse to write to it.
+        Create,
+        /// Add the lines that haven't been ingested yet.
+        Append,
+        /// Drop it and ingest everything again.
+        Replace,
+    }
+
+    impl FromStr for Mode {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "create" => Ok(Mode::Create),
+                "append" => Ok(Mode::Append),
+                "replace" => Ok(Mode::Replace),
+                _ => Err(format!("unknown mode {:?}, expected create, append or replace", s)),
+            }
+        }
+    }
+
+    /// Where and how results are written. Settings left out fall back to
+    /// `output.db`, no table prefix, append mode and `failures.log`.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct Config {
+        pub database: Option<PathBuf>,
+        pub table_prefix: Option<String>,
+        pub mode: Option<Mode>,
+        pub failures_log: Option<PathBuf>,
+    }
+
+    impl Config {
+        /// Loads a config file, a JSON object with any of the fields above.
+        pub fn load<P: AsRef<Path>>(filename: P) -> Result<Self, Error> {
+            Ok(serde_json::from_reader(BufReader::new(File::open(filename)?))?)
+        }
+
+        /// Takes the settings missing from `self` from `other`.
+        pub fn or(self, other: Config) -> Config {
+            Config {
+                database: self.database.or(other.database),
+                table_prefix: self.table_prefix.or(other.table_prefix),
+                mode: self.mode.or(other.mode),
+                failures_log: self.failures_log.or(other.failures_log),
+            }
+        }
+
+        pub fn database(&self) -> &Path {
+            self.database.as_ref().map_or(Path::new("output.db"), PathBuf::as_path)
+        }
+
+        pub fn mode(&self) -> Mode {
+            self.mode.unwrap_or(Mode::Append)
+        }
+
+        pub fn failures_log(&self) -> &Path {
+            self.failures_log.as_ref().map_or(Path::new("failures.log"), PathBuf::as_path)
+        }
+
+        /// Opens the failures log to add to, or emptied in replace mode as
+        /// the lines in it are read again.
+        pub fn open_failures_log(&self) -> Result<File, Error> {
+            let mut options = OpenOptions::new();
+            match self.mode() {
+                Mode::Replace => options.write(true).truncate(true),
+                Mode::Create | Mode::Append => options.append(true),
+            };
+            Ok(options.create(true).open(self.failures_log())?)
+        }
+
+        /// The name of table `name` after prefixing.
+        pub fn table_name(&self, name: &str) -> Result<String, Error> {
+            let prefix = self.table_prefix.as_ref().map_or("", String::as_str);
+            // Unquoted SQL identifiers can't start with a digit.
+            if prefix.starts_with(|c: char| c.is_ascii_digit()) || !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(Error::InvalidPrefix(prefix.to_string()));
+            }
+            Ok(format!("{}{}", prefix, name))
+        }
+    }
+
+    /// Adds the `columns`, given as name and type, that `table` is missing,
+    /// so that tables written by older versions can still be appended to.
+    pub fn add_columns(conn: &Connection, table: &str, columns: &[(&str, &str)]) -> Result<(), Error> {
+        let existing: Vec<String> = {
+            let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+            let cols = stmt.query_map(&[], |row| row.get(1))?.collect::<Result<_, _>>()?;
+            cols
+        };
+        for &(name, sql_type) in columns {
+            if !existing.iter().any(|col| col == name) {
+                conn.execute(&format!("ALTER TABLE {} ADD {} {}", table, name, sql_type), &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Readies `table` and its ledger for a run in `mode`, before the table
+    /// is created.
+    pub fn prepare_table(conn: &Connection, mode: Mode, table: &str) -> Result<(), Error> {
+        match mode {
+            Mode::Create => {
+                let exists: i64 = conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?", &[&table], |row| row.get(0))?;
+                if exists > 0 {
+                    return Err(Error::TableExists(table.to_string()));
+                }
+                Ledger::clear(conn, table)?;
+            },
+            Mode::Append => {},
+            Mode::Replace => {
+                conn.execute(&format!("DROP TABLE IF EXISTS {}", table), &[])?;
+                Ledger::clear(conn, table)?;
+            },
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{prepare_table, Config, Mode};
+        use ledger::Ledger;
+        use rusqlite::Connection;
+        use serde_json;
+        use std::env;
+        use std::fs;
+        use std::io::Write;
+        use std::path::Path;
+
+        #[test]
+        fn config() {
+            let file: Config = serde_json::from_str(r#"{"database": "sites.db", "table_prefix": "site1_", "mode": "append"}"#).unwrap();
+            let flags = Config { mode: Some(Mode::Replace), ..Config::default() };
+            let config = flags.or(file);
+            assert_eq!(config.database(), Path::new("sites.db"));
+            assert_eq!(config.mode(), Mode::Replace);
+            assert_eq!(config.failures_log(), Path::new("failures.log"));
+            assert_eq!(config.table_name("http_logs").unwrap(), "site1_http_logs");
+            assert!(Config { table_prefix: Some("a; DROP".to_string()), ..Config::default() }.table_name("http_logs").is_err());
+            assert!(Config { table_prefix: Some("1site_".to_string()), ..Config::default() }.table_name("http_logs").is_err());
+            assert_eq!(Config { table_prefix: Some("_1site_".to_string()), ..Config::default() }.table_name("http_logs").unwrap(), "_1site_http_logs");
+            assert!(serde_json::from_str::<Config>(r#"{"databse": "x.db"}"#).is_err());
+        }
+
+        #[test]
+        fn modes() {
+            let conn = Connection::open_in_memory().unwrap();
+            prepare_table(&conn, Mode::Create, "logs").unwrap();
+            conn.execute("CREATE TABLE logs (line TEXT)", &[]).unwrap();
+            conn.execute("INSERT INTO logs VALUES ('one')", &[]).unwrap();
+            Ledger::load(&conn, "logs").unwrap();
+            assert!(prepare_table(&conn, Mode::Create, "logs").is_err());
+            prepare_table(&conn, Mode::Append, "logs").unwrap();
+            let count: i64 = conn.query_row("SELECT COUNT(*) FROM logs", &[], |row| row.get(0)).unwrap();
+            assert_eq!(count, 1);
+            prepare_table(&conn, Mode::Replace, "logs").unwrap();
+            let tables: i64 = conn.query_row("SELECT COUNT(*) FROM sqlite_master", &[], |row| row.get(0)).unwrap();
+            assert_eq!(tables, 0);
+        }
+
+        #[test]
+        fn open_failures_log() {
+            let path = env::temp_dir().join(format!("parse-logs-failures-{}.log", ::std::process::id()));
+            let config = |mode| Config { mode: Some(mode), failures_log: Some(path.clone()), ..Config::default() };
+            config(Mode::Append).open_failures_log().unwrap().write_all(b"one\n").unwrap();
+            config(Mode::Append).open_failures_log().unwrap().write_all(b"two\n").unwrap();
+            assert_eq!(fs::read(&path).unwrap(), b"one\ntwo\n");
+            config(Mode::Replace).open_failures_log().unwrap().write_all(b"three\n").unwrap();
+            assert_eq!(fs::read(&path).unwrap(), b"three\n");
+            fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn add_columns() {
+            let conn = Connection::open_in_memory().unwrap();
+            conn.execute("CREATE TABLE logs (line TEXT)", &[]).unwrap();
+            super::add_columns(&conn, "logs", &[("line", "TEXT"), ("vendor", "TEXT")]).unwrap();
+            super::add_columns(&conn, "logs", &[("vendor", "TEXT")]).unwrap();
+            conn.execute("INSERT INTO logs (line, vendor) VALUES ('one', 'Apple, Inc.')", &[]).unwrap();
+        }
+    }
+}
+
+pub mod filter {
+    use globset::{self, GlobBuilder, GlobSet, GlobSetBuilder};
+    use regex::{self, RegexSet, RegexSetBuilder};
+    use serde_json;
+    use std::fmt;
+    use std::fs::File;
+    use std::io::{self, BufReader};
+    use std::path::Path;
+    use MacAddr;
+
+    #[derive(Debug)]
+    pub enum Error {
+        Io(io::Error),
+        Json(serde_json::Error),
+        Glob(globset::Error),
+        Regex(regex::Error),
+        InvalidMac(String),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                Error::Io(ref e) => write!(f, "{}", e),
+                Error::Json(ref e) => write!(f, "{}", e),
+                Error::Glob(ref e) => write!(f, "{}", e),
+                Error::Regex(ref e) => write!(f, "{}", e),
+                Error::InvalidMac(ref mac) => write!(f, "invalid MAC address or prefix {:?}", mac),
+            }
+        }
+    }
+
+    impl ::std::error::Error for Error {}
+
+    impl From<io::Error> for Error {
+        fn from(e: io::Error) -> Self {
+            Error::Io(e)
+        }
+    }
+
+    impl From<serde_json::Error> for Error {
+        fn from(e: serde_json::Error) -> Self {
+            Error::Json(e)
+        }
+    }
+
+    impl From<globset::Error> for Error {
+        fn from(e: globset::Error) -> Self {
+            Error::Glob(e)
+        }
+    }
+
+    impl From<regex::Error> for Error {
+        fn from(e: regex::Error) -> Self {
+            Error::Regex(e)
+        }
+    }
+
+    /// Rules that each match devices by hostname or MAC address. Hostnames
+    /// are compared case-insensitively.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct Rules {
+        /// Glob patterns, so a plain name matches just that name.
+        pub hostnames: Vec<String>,
+        pub hostname_regexes: Vec<String>,
+        /// MAC addresses, or prefixes of them such as the OUI `9c:ad:97`.
+        pub macs: Vec<String>,
+        /// Glob patterns of the vendors of MAC addresses, such as
+        /// `Raspberry Pi*`.
+        pub vendors: Vec<String>,
+    }
+
+    impl Rules {
+        pub fn is_empty(&self) -> bool {
+            self.hostnames.is_empty() && self.hostname_regexes.is_empty() && self.macs.is_empty() && self.vendors.is_empty()
+        }
+
+        /// Adds the rules of `other` to these.
+        pub fn extend(&mut self, other: Rules) {
+            self.hostnames.extend(other.hostnames);
+            self.hostname_regexes.extend(other.hostname_regexes);
+            self.macs.extend(other.macs);
+            self.vendors.extend(other.vendors);
+        }
+    }
+
+    /// The devices to keep traffic from, as read from a filter file.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct FilterConfig {
+        pub allow: Rules,
+        pub deny: Rules,
+    }
+
+    impl FilterConfig {
+        /// Loads a JSON filter file, such as
+        /// `{"allow": {"hostnames": ["*-ipad"]}, "deny": {"macs": ["9c:ad:97"]}}`.
+        pub fn load<P: AsRef<Path>>(filename: P) -> Result<Self, Error> {
+            Ok(serde_json::from_reader(BufReader::new(File::open(filename)?))?)
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct Matcher {
+        hostnames: GlobSet,
+        hostname_regexes: RegexSet,
+        macs: Vec<Vec<u8>>,
+        vendors: GlobSet,
+    }
+
+    impl Matcher {
+        fn new(rules: &Rules) -> Result<Self, Error> {
+            Ok(Matcher {
+                hostnames: case_insensitive_globs(&rules.hostnames)?,
+                hostname_regexes: RegexSetBuilder::new(&rules.hostname_regexes).case_insensitive(true).build()?,
+                macs: rules.macs.iter().map(|mac| mac_prefix(mac).ok_or_else(|| Error::InvalidMac(mac.clone()))).collect::<Result<_, _>>()?,
+                vendors: case_insensitive_globs(&rules.vendors)?,
+            })
+        }
+
+        fn matches(&self, mac_addr: Option<MacAddr>, hostname: Option<&str>) -> bool {
+            hostname.is_some_and(|hostname| self.hostnames.is_match(hostname) || self.hostname_regexes.is_match(hostname))
+                || mac_addr.is_some_and(|mac_addr| self.macs.iter().any(|prefix| mac_addr.0.starts_with(prefix)))
+                || mac_addr.and_then(|mac_addr| mac_addr.vendor()).is_some_and(|vendor| self.vendors.is_match(vendor))
+        }
+    }
+
+    fn case_insensitive_globs(patterns: &[String]) -> Result<GlobSet, Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(GlobBuilder::new(pattern).case_insensitive(true).build()?);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Parses a MAC address, or the first octets of one.
+    fn mac_prefix(s: &str) -> Option<Vec<u8>> {
+        let prefix = s.split([':', '-'])
+            .map(|part| if part.len() == 2 { u8::from_str_radix(part, 16).ok() } else { None })
+            .collect::<Option<Vec<u8>>>()?;
+        if prefix.len() > 6 {
+            return None;
+        }
+        Some(prefix)
+    }
+
+    /// Decides which devices' traffic is kept: those that match an allow
+    /// rule, or all when there are none, unless they match a deny rule.
+    #[derive(Debug, Clone)]
+    pub struct DeviceFilter {
+        allow: Option<Matcher>,
+        deny: Matcher,
+    }
+
+    impl DeviceFilter {
+        pub fn new(config: &FilterConfig) -> Result<Self, Error> {
+            Ok(DeviceFilter {
+                allow: if config.allow.is_empty() { None } else { Some(Matcher::new(&config.allow)?) },
+                deny: Matcher::new(&config.deny)?,
+            })
+        }
+
+        pub fn keep(&self, mac_addr: Option<MacAddr>, hostname: Option<&str>) -> bool {
+            self.allow.as_ref().is_none_or(|allow| allow.matches(mac_addr, hostname)) && !self.deny.matches(mac_addr, hostname)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{DeviceFilter, FilterConfig, Rules};
+        use serde_json;
+        use MacAddr;
+
+        #[test]
+        fn keep() {
+            let mac: MacAddr = "9c:ad:97:d1:65:39".parse().unwrap();
+            let other_mac: MacAddr = "00:11:22:33:44:55".parse().unwrap();
+            let no_filter = DeviceFilter::new(&FilterConfig::default()).unwrap();
+            assert!(no_filter.keep(None, None));
+            assert!(no_filter.keep(Some(mac), Some("joe")));
+
+            let config: FilterConfig = serde_json::from_str(r#"{
+                "allow": {"hostnames": ["joe", "*-iPad"], "hostname_regexes": ["^ashleys"], "macs": ["00:11:22"]},
+                "deny": {"hostnames": ["hanks-ipad"], "macs": ["9c-ad-97-d1-65-39"]}
+            }"#).unwrap();
+            let filter = DeviceFilter::new(&config).unwrap();
+            assert!(!filter.keep(None, None));
+            assert!(filter.keep(None, Some("Joe")));
+            assert!(!filter.keep(None, Some("joey")));
+            assert!(filter.keep(None, Some("robins-ipad")));
+            assert!(!filter.keep(None, Some("hanks-ipad")));
+            assert!(filter.keep(None, Some("ashleysplewatch")));
+            assert!(filter.keep(Some(other_mac), None));
+            assert!(!filter.keep(Some(mac), Some("joe")));
+
+            let deny_only = FilterConfig { deny: Rules { hostnames: vec!["joe".to_string()], ..Rules::default() }, ..FilterConfig::default() };
+            let filter = DeviceFilter::new(&deny_only).unwrap();
+            assert!(filter.keep(None, None));
+            assert!(!filter.keep(Some(mac), Some("joe")));
+
+            let pi: MacAddr = "b8:27:eb:12:34:56".parse().unwrap();
+            let raspberry_pis = FilterConfig { allow: Rules { vendors: vec!["raspberry pi*".to_string()], ..Rules::default() }, ..FilterConfig::default() };
+            let filter = DeviceFilter::new(&raspberry_pis).unwrap();
+            assert!(filter.keep(Some(pi), None));
+            assert!(!filter.keep(Some(mac), None));
+        }
+
+        #[test]
+        fn invalid() {
+            let bad_mac = FilterConfig { allow: Rules { macs: vec!["9c:ad:9".to_string()], ..Rules::default() }, ..FilterConfig::default() };
+            assert!(DeviceFilter::new(&bad_mac).is_err());
+            let bad_regex = FilterConfig { allow: Rules { hostname_regexes: vec!["(".to_string()], ..Rules::default() }, ..FilterConfig::default() };
+            assert!(DeviceFilter::new(&bad_regex).is_err());
+            assert!(serde_json::from_str::<FilterConfig>(r#"{"allow": {"hosts": []}}"#).is_err());
+        }
+
+        #[test]
+        fn example() {
+            let config = FilterConfig::load(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/device_filter.json")).unwrap();
+            let filter = DeviceFilter::new(&config).unwrap();
+            assert!(filter.keep(None, Some("robins-ipad")));
+            assert!(filter.keep(None, Some("joe")));
+            assert!(!filter.keep(None, Some("joey")));
+            assert!(!filter.keep(None, None));
+        }
+    }
 }